@@ -1,6 +1,6 @@
 use crate::{tokens, token_list};
-use crate::defs::{tiny_atom, token, OpalHeader, PacketHeader, SimpleToken, SubpacketHeader, Token, TokenStream, BS8};
-use alloc::{borrow::ToOwned, vec::Vec};
+use crate::defs::{tiny_atom, token, ComPacketHeader, OpalError, OpalHeader, PacketHeader, SimpleToken, SubpacketHeader, Token, TokenStream, BS8};
+use alloc::{borrow::ToOwned, boxed::Box, vec::Vec};
 use core::mem::{size_of, size_of_val};
 
 pub struct OpalCommandBuilder {
@@ -38,7 +38,7 @@ impl OpalCommandBuilder {
             .write(&mut self.payload);
         }
         header.subpkt.length = self.payload.len() as u32;
-        while self.payload.len() % 4 != 0 {
+        while !self.payload.len().is_multiple_of(4) {
             self.payload.push(0);
         }
         header.pkt.length = (self.payload.len() + size_of::<SubpacketHeader>()) as u32;
@@ -86,44 +86,41 @@ pub struct OpalResponse {
 }
 
 impl OpalResponse {
-    pub fn parse(header: OpalHeader, bytes: &[u8]) -> Self {
-        let mut tokens = Vec::new();
-        let offset = size_of_val(&header);
-
-        let mut pos = offset;
-
-        let len = header.subpkt.length as usize + offset;
+    /// Parses a received ComPacket, checking every length against the buffer and the enclosing header
+    pub fn parse(bytes: &[u8]) -> Result<Self, OpalError> {
+        let (header, payload) = com_packet_payload(bytes)?;
 
-        while pos < len {
-            let token_len = if bytes[pos] & 0x80 == 0 {
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        while pos < payload.len() {
+            let length_byte = |i: usize| payload.get(pos + i).map(|&b| b as usize).ok_or(OpalError::MalformedResponse);
+            let token_len = if payload[pos] & 0x80 == 0 {
                 // tiny atom
                 1
-            } else if bytes[pos] & 0x40 == 0 {
+            } else if payload[pos] & 0x40 == 0 {
                 // short atom
-                (bytes[pos] as usize & 0x0F) + 1
-            } else if bytes[pos] & 0x20 == 0 {
+                (payload[pos] as usize & 0x0F) + 1
+            } else if payload[pos] & 0x20 == 0 {
                 // medium atom
-                (((bytes[pos] as usize & 0x07) << 8) | bytes[pos + 1] as usize) + 2
-            } else if bytes[pos] & 0x10 == 0 {
+                (((payload[pos] as usize & 0x07) << 8) | length_byte(1)?) + 2
+            } else if payload[pos] & 0x10 == 0 {
                 // long atom
-                (((bytes[pos + 1] as usize) << 16)
-                    | ((bytes[pos + 2] as usize) << 8)
-                    | bytes[pos + 3] as usize)
-                    + 4
+                ((length_byte(1)? << 16) | (length_byte(2)? << 8) | length_byte(3)?) + 4
             } else {
                 // token
                 1
             };
+            let token = payload.get(pos..pos + token_len).ok_or(OpalError::MalformedResponse)?;
             // skip empty atoms
-            if token_len != 1 || bytes[pos] != 0xFF {
-                tokens.push(bytes[pos..pos + token_len as usize].to_owned());
+            if token != [0xFF] {
+                tokens.push(token.to_owned());
             }
-            pos += token_len as usize;
+            pos += token_len;
         }
 
         tracing::trace!("parsed tokens: {:X?}", tokens);
 
-        Self { header, tokens }
+        Ok(Self { header, tokens })
     }
 
     pub fn len(&self) -> usize {
@@ -138,41 +135,259 @@ impl OpalResponse {
         self.tokens.get(index).map(Vec::as_slice) == Some(&[token.token])
     }
 
-    pub fn get_uint(&self, index: usize) -> u64 {
-        let token = &self.tokens[index];
+    /// The tokens as a tree of lists and named values, `None` if they are not well-formed
+    pub fn values(&self) -> Option<Vec<Value>> {
+        let mut tokens = self.tokens.iter().map(Vec::as_slice);
+        let mut values = Vec::new();
+        while let Some(token) = tokens.next() {
+            values.push(Value::parse(token, &mut tokens, 0)?);
+        }
+        Some(values)
+    }
 
-        if token[0] & 0x80 == 0 {
-            // tiny atom
-            if token[0] & 0x40 == 0 {
-                (token[0] & 0x3f) as u64
-            } else {
-                panic!("unsigned int requested for signed tiny atom")
-            }
-        } else if token[0] & 0x40 == 0 {
-            // short atom
-            if token[0] & 0x10 == 0 {
-                let mut whatever = 0;
-                if token.len() > 9 {
-                    panic!("u64 with more than 8 bytes");
+    /// The results of a method response: `[ results ] EOD [ status ]`
+    pub fn results(&self) -> Option<Vec<Value>> {
+        match self.values()?.into_iter().next()? {
+            Value::List(results) => Some(results),
+            _ => None,
+        }
+    }
+
+    /// Unsigned integer atom at `index`, `None` if there is none or it's not an unsigned integer
+    pub fn get_uint(&self, index: usize) -> Option<u64> {
+        match Value::parse_atom(self.tokens.get(index)?)? {
+            Value::Uint(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a received ComPacket into its header and the payload of its (first) subpacket.
+/// A ComPacket without any packets has an empty payload.
+pub fn com_packet_payload(bytes: &[u8]) -> Result<(OpalHeader, &[u8]), OpalError> {
+    let header = parse_header(bytes).ok_or(OpalError::MalformedComPacket)?;
+    let offset = size_of_val(&header);
+
+    let cp_len = header.cp.length as usize;
+    let pkt_len = header.pkt.length as usize;
+    let subpkt_len = header.subpkt.length as usize;
+    if cp_len == 0 {
+        return Ok((header, &[]));
+    }
+    let fits = cp_len <= bytes.len() - size_of::<ComPacketHeader>()
+        && pkt_len.saturating_add(size_of::<PacketHeader>()) <= cp_len
+        && subpkt_len.saturating_add(size_of::<SubpacketHeader>()) <= pkt_len;
+    if !fits {
+        return Err(OpalError::MalformedComPacket);
+    }
+    Ok((header, &bytes[offset..offset + subpkt_len]))
+}
+
+/// Reads the big endian ComPacket, Packet and Subpacket headers into native endianness
+pub fn parse_header(bytes: &[u8]) -> Option<OpalHeader> {
+    let u16_at = |offset: usize| Some(u16::from_be_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?));
+    let u32_at = |offset: usize| Some(u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?));
+
+    let mut header = OpalHeader::default();
+    header.cp.extended_com_id = bytes.get(4..8)?.try_into().ok()?;
+    header.cp.outstanding_data = u32_at(8)?;
+    header.cp.min_transfer = u32_at(12)?;
+    header.cp.length = u32_at(16)?;
+    header.pkt.tsn = u32_at(20)?;
+    header.pkt.hsn = u32_at(24)?;
+    header.pkt.seq_number = u32_at(28)?;
+    header.pkt.ack_type = u16_at(34)?;
+    header.pkt.acknowledgement = u32_at(36)?;
+    header.pkt.length = u32_at(40)?;
+    header.subpkt.kind = u16_at(50)?;
+    header.subpkt.length = u32_at(52)?;
+    Some(header)
+}
+
+/// A parsed token of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Uint(u64),
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Name(Box<Value>, Box<Value>),
+    /// control tokens outside of lists and names, e.g. CALL or ENDOFDATA
+    Control(u8),
+}
+
+/// Lists and names nested deeper than this are malformed; real responses need a handful of levels,
+/// while unbounded recursion lets a drive overflow the stack
+const MAX_NESTING: usize = 32;
+
+impl Value {
+    fn parse<'a>(token: &'a [u8], rest: &mut impl Iterator<Item = &'a [u8]>, depth: usize) -> Option<Self> {
+        match *token.first()? {
+            0xF0 | 0xF2 if depth >= MAX_NESTING => None,
+            0xF0 => {
+                let mut list = Vec::new();
+                loop {
+                    match rest.next()? {
+                        [0xF1] => return Some(Value::List(list)),
+                        token => list.push(Value::parse(token, rest, depth + 1)?),
+                    }
                 }
-                let mut i = token.len();
-                for b in 0..token.len() {
-                    i -= 1;
-                    whatever |= (token[i] as u64) << (8 * b);
+            }
+            0xF2 => {
+                let name = Value::parse(rest.next()?, rest, depth + 1)?;
+                let value = Value::parse(rest.next()?, rest, depth + 1)?;
+                match rest.next()? {
+                    [0xF3] => Some(Value::Name(Box::new(name), Box::new(value))),
+                    _ => None,
                 }
-                whatever
-            } else {
-                panic!("unsigned int requested for signed short atom")
             }
-        } else if (token[0] & 0x20) == 0 {
-            // medium atom
-            panic!("unsigned int requested for medium atom is unsupported");
-        } else if (token[0] & 0x10) == 0 {
-            // long atom
-            panic!("unsigned int requested for long atom is unsupported");
+            0xF8..=0xFC => Some(Value::Control(token[0])),
+            _ => Value::parse_atom(token),
+        }
+    }
+
+    fn parse_atom(token: &[u8]) -> Option<Self> {
+        // (is byte string, is signed, data)
+        let (bytes, signed, data) = match *token.first()? {
+            b @ 0x00..=0x7F if b & 0x40 == 0 => return Some(Value::Uint(b as u64)),
+            // sign extend the 6 bit value
+            b @ 0x00..=0x7F => return Some(Value::Int(((b << 2) as i8 >> 2) as i64)),
+            b @ 0x80..=0xBF => (b & 0x20 != 0, b & 0x10 != 0, token.get(1..)?),
+            b @ 0xC0..=0xDF => (b & 0x10 != 0, b & 0x08 != 0, token.get(2..)?),
+            b @ 0xE0..=0xE3 => (b & 0x02 != 0, b & 0x01 != 0, token.get(4..)?),
+            _ => return None,
+        };
+        if bytes {
+            return Some(Value::Bytes(data.to_vec()));
+        }
+        // integers may be wider than 8 bytes as long as the value fits
+        let (padding, data) = data.split_at(data.len().saturating_sub(8));
+        if signed {
+            let negative = data.first().is_some_and(|&b| b & 0x80 != 0);
+            let pad = if negative { 0xFF } else { 0x00 };
+            if padding.iter().any(|&b| b != pad) {
+                return None;
+            }
+            let mut buf = [pad; 8];
+            buf[8 - data.len()..].copy_from_slice(data);
+            Some(Value::Int(i64::from_be_bytes(buf)))
         } else {
-            // token
-            panic!("unsigned int requested for token is unsupported");
+            if padding.iter().any(|&b| b != 0) {
+                return None;
+            }
+            let mut buf = [0; 8];
+            buf[8 - data.len()..].copy_from_slice(data);
+            Some(Value::Uint(u64::from_be_bytes(buf)))
+        }
+    }
+
+    pub fn as_uint(&self) -> Option<u64> {
+        match *self {
+            Value::Uint(v) => Some(v),
+            Value::Int(v) => v.try_into().ok(),
+            _ => None,
         }
     }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Value::Uint(v) => v.try_into().ok(),
+            Value::Int(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.as_uint()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    /// Value of the `name = value` pair within this list
+    pub fn get(&self, name: &Value) -> Option<&Value> {
+        self.as_list()?.iter().find_map(|value| match value {
+            Value::Name(n, v) if **n == *name => Some(&**v),
+            _ => None,
+        })
+    }
+}
+
+impl Token for Value {
+    fn write(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Value::Uint(v) => v.write(buffer),
+            Value::Int(v) if (-32..32).contains(v) => buffer.push(0x40 | (*v as u8 & 0x3F)),
+            Value::Int(v) => {
+                // shortest big endian two's complement representation
+                let bytes = v.to_be_bytes();
+                let skip = (0..7)
+                    .take_while(|&i| {
+                        let pad = if *v < 0 { 0xFF } else { 0x00 };
+                        bytes[i] == pad && (bytes[i + 1] & 0x80) == (pad & 0x80)
+                    })
+                    .count();
+                buffer.push(0x90 | (8 - skip) as u8);
+                buffer.extend(&bytes[skip..]);
+            }
+            Value::Bytes(bytes) => bytes.as_slice().write(buffer),
+            Value::List(list) => {
+                token::STARTLIST.write(buffer);
+                list.iter().for_each(|value| value.write(buffer));
+                token::ENDLIST.write(buffer);
+            }
+            Value::Name(name, value) => {
+                token::STARTNAME.write(buffer);
+                name.write(buffer);
+                value.write(buffer);
+                token::ENDNAME.write(buffer);
+            }
+            Value::Control(token) => buffer.push(*token),
+        }
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Uint(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Uint(b as u64)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Self {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<BS8> for Value {
+    fn from(uid: BS8) -> Self {
+        Value::Bytes(uid.bytes.to_vec())
+    }
 }
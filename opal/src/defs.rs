@@ -101,6 +101,8 @@ impl core::fmt::Debug for BS8 {
 macro_rules! simple_tokens {
     ($($name:ident = $value:literal;)*) => {
         $(
+            // the tables mirror the TCG specs and sedutil, not every entry is used
+            #[allow(dead_code)]
             pub const $name: $crate::defs::SimpleToken = $crate::defs::SimpleToken {
                 token: $value,
                 #[cfg(debug_assertions)]
@@ -113,6 +115,8 @@ macro_rules! simple_tokens {
 macro_rules! bytestrings {
     ($($name:ident = $value:literal;)*) => {
         $(
+            // the tables mirror the TCG specs and sedutil, not every entry is used
+            #[allow(dead_code)]
             pub const $name: $crate::defs::BS8 = $crate::defs::BS8 {
                 bytes: {
                     let u: u64 = $value;
@@ -129,6 +133,7 @@ pub mod method {
     bytestrings! {
         PROPERTIES = 0xFF01;
         STARTSESSION = 0xFF02;
+        SYNCSESSION = 0xFF03;
         REVERT = 0x600000202;
         ACTIVATE = 0x600000203;
         EGET = 0x600000006;
@@ -163,6 +168,7 @@ pub mod uid {
         ENTERPRISE_BANDMASTER0 = 0x900008001;
         ENTERPRISE_ERASEMASTER = 0x900008401;
         // tables
+        OPAL_LOCKING_TABLE = 0x80200000000;
         OPAL_LOCKINGRANGE_GLOBAL = 0x80200000001;
         OPAL_LOCKINGRANGE_ACE_RDLOCKED = 0x80003E001;
         OPAL_LOCKINGRANGE_ACE_WRLOCKED = 0x80003E801;
@@ -171,6 +177,7 @@ pub mod uid {
         OPAL_MBRCONTROL_SET_DONE_TO_DOR = 0x80003F801;
         OPAL_MBRCONTROL = 0x80300000001;
         OPAL_MBR = 0x80400000000;
+        OPAL_DATASTORE = 0x100100000000;
         OPAL_AUTHORITY_TABLE = 0x900000000;
         OPAL_C_PIN_TABLE = 0xB00000000;
         OPAL_LOCKING_INFO_TABLE = 0x80100000001;
@@ -185,6 +192,44 @@ pub mod uid {
         // omitted optional parameter
         OPAL_UID_HEXFF = 0xFFFFFFFFFFFFFFFF;
     }
+
+    /// Row of the Opal Locking table, 0 being the global range
+    pub fn locking_range(n: u8) -> super::BS8 {
+        match n {
+            0 => OPAL_LOCKINGRANGE_GLOBAL,
+            n => super::BS8::new((0x0000_0802_0003_0000 + n as u64).to_be_bytes(), "LOCKING_RANGE_N"),
+        }
+    }
+
+    /// Admin `n` of the Locking SP, starting at 1
+    pub fn admin(n: u8) -> super::BS8 {
+        super::BS8::new((0x0000_0009_0001_0000 + n as u64).to_be_bytes(), "ADMIN_N")
+    }
+
+    /// User `n` of the Locking SP, starting at 1
+    pub fn user(n: u8) -> super::BS8 {
+        super::BS8::new((0x0000_0009_0003_0000 + n as u64).to_be_bytes(), "USER_N")
+    }
+
+    /// C_PIN row of Admin `n` of the Locking SP
+    pub fn c_pin_admin(n: u8) -> super::BS8 {
+        super::BS8::new((0x0000_000B_0001_0000 + n as u64).to_be_bytes(), "C_PIN_ADMIN_N")
+    }
+
+    /// C_PIN row of User `n` of the Locking SP
+    pub fn c_pin_user(n: u8) -> super::BS8 {
+        super::BS8::new((0x0000_000B_0003_0000 + n as u64).to_be_bytes(), "C_PIN_USER_N")
+    }
+
+    /// Row of the Enterprise Locking table, band 0 being the global range
+    pub fn band(n: u8) -> super::BS8 {
+        super::BS8::new((0x0000_0802_0000_0001 + n as u64).to_be_bytes(), "BAND_N")
+    }
+
+    /// Enterprise authority owning band `n`
+    pub fn band_master(n: u8) -> super::BS8 {
+        super::BS8::new((0x0000_0009_0000_8001 + n as u64).to_be_bytes(), "BANDMASTER_N")
+    }
 }
 
 impl From<bool> for SimpleToken {
@@ -284,7 +329,7 @@ pub mod short_atom {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum LockingState {
     ReadWrite = 0x01,
     ReadOnly = 0x02,
@@ -299,10 +344,63 @@ impl Token for LockingState {
     }
 }
 
+/// Signing authority of an Opal drive: SID of the Admin SP or an Admin / User of the Locking SP
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Authority {
+    Sid,
+    Admin(u8),
+    User(u8),
+}
+
+impl Authority {
+    pub fn uid(self) -> BS8 {
+        match self {
+            Authority::Sid => uid::OPAL_SID,
+            Authority::Admin(n) => uid::admin(n),
+            Authority::User(n) => uid::user(n),
+        }
+    }
+
+    /// Exclusive owner of locking range `n` in Single User Mode: User1 for the global range,
+    /// User2 for range 1 and so on
+    pub fn single_user(n: u8) -> Self {
+        Authority::User(n + 1)
+    }
+
+    /// The SP the authority belongs to
+    pub fn sp(self) -> BS8 {
+        match self {
+            Authority::Sid => uid::OPAL_ADMINSP,
+            Authority::Admin(_) | Authority::User(_) => uid::OPAL_LOCKINGSP,
+        }
+    }
+
+    /// The C_PIN row holding the authority's password
+    pub fn c_pin(self) -> BS8 {
+        match self {
+            Authority::Sid => uid::OPAL_C_PIN_SID,
+            Authority::Admin(n) => uid::c_pin_admin(n),
+            Authority::User(n) => uid::c_pin_user(n),
+        }
+    }
+}
+
+impl Default for Authority {
+    fn default() -> Self {
+        Authority::Admin(1)
+    }
+}
+
 #[derive(Debug, Copy, Clone, snafu::Snafu)]
 pub enum OpalError {
     Status { code: StatusCode },
     NoMethodStatus,
+    MalformedComPacket,
+    MalformedResponse,
+    CommandTooLarge,
+    StackResetFailed,
+    /// the TPer kept answering that the response isn't ready yet
+    NoResponse,
 }
 
 uefi_raw::newtype_enum! {
@@ -414,10 +512,9 @@ impl Token for &[u8] {
             buffer.push(0xD0 | ((self.len() >> 8) & 0x07) as u8);
             buffer.push((self.len() & 0xff) as u8);
         } else {
-            panic!(
-                "Bytestring too large ({} > 2048) to use in OpalPacket",
-                self.len()
-            );
+            // long atom len, whether it fits is up to the negotiated MaxIndTokenSize
+            buffer.push(0xE2);
+            buffer.extend(&(self.len() as u32).to_be_bytes()[1..]);
         }
         buffer.extend(*self);
     }
@@ -1,8 +1,18 @@
 use alloc::fmt::{Debug, Display};
 
+use alloc::format;
 use snafu::{ResultExt, AsErrorSource};
 use uefi_raw::newtype_enum;
 
+use crate::defs::OpalError;
+
+/// request code of ComID management
+const STACK_RESET: u32 = 0x0000_0002;
+/// times a response that isn't ready yet is polled before giving up
+pub(crate) const MAX_POLLS: usize = 1000;
+/// microseconds to wait before polling the TPer again for a response that isn't ready yet
+pub(crate) const POLL_INTERVAL_US: usize = 1000;
+
 pub trait SecureProtocol {
     type Error: Debug + Display + AsErrorSource;
 
@@ -11,6 +21,8 @@ pub trait SecureProtocol {
     /// Very unsafe and might even brick a device if used incorrectly.
     unsafe fn secure_send(&mut self, protocol: u8, com_id: u16, data: &mut [u8]) -> Result<(), Self::Error>;
 
+    /// # Safety
+    /// See [`SecureProtocol::secure_send`].
     unsafe fn secure_recv(
         &mut self,
         protocol: u8,
@@ -20,6 +32,9 @@ pub trait SecureProtocol {
 
     fn reconnect_controller(&mut self) -> Result<(), Self::Error>;
 
+    /// Waits `microseconds` before the TPer is polled again, e.g. with the Stall boot service.
+    fn stall(&mut self, microseconds: usize);
+
     fn align(&self) -> usize;
 
     fn serial_num(&self) -> &[u8];
@@ -27,19 +42,43 @@ pub trait SecureProtocol {
 
 newtype_enum! {
     pub enum FeatureCodes: u16 => {
-        // TPER       = 0x0001,
-        LOCKING    = 0x0002,
-        // GEOMETRY   = 0x0003,
-        ENTERPRISE = 0x0100,
-        // DATASTORE  = 0x0202,
-        // SINGLEUSER = 0x0201,
-        // OPAL_V1    = 0x0200,
-        OPAL_V2    = 0x0203,
+        TPER        = 0x0001,
+        LOCKING     = 0x0002,
+        GEOMETRY    = 0x0003,
+        ENTERPRISE  = 0x0100,
+        OPAL_V1     = 0x0200,
+        SINGLE_USER = 0x0201,
+        DATASTORE   = 0x0202,
+        OPAL_V2     = 0x0203,
+        PYRITE_V1   = 0x0302,
+        PYRITE_V2   = 0x0303,
+        RUBY        = 0x0304,
+        BLOCK_SID   = 0x0402,
+    }
+}
+
+newtype_enum! {
+    /// Initial value of C_PIN_SID and its value after a TPer revert, as reported by the SSC descriptors.
+    pub enum PinIndicator: u8 => {
+        MSID          = 0x00,
+        VENDOR_UNIQUE = 0xFF,
     }
 }
 
 bitflags::bitflags! {
-    #[derive(Debug)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct TperFlags: u8 {
+        const SYNC_SUPPORTED       = 0x01;
+        const ASYNC_SUPPORTED      = 0x02;
+        const ACK_NAK_SUPPORTED    = 0x04;
+        const BUFFER_MGMT_SUPPORTED = 0x08;
+        const STREAMING_SUPPORTED  = 0x10;
+        const COMID_MGMT_SUPPORTED = 0x40;
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub struct LockingFlags: u8 {
         const LOCKING_SUPPORTED = 0x01;
         const LOCKING_ENABLED   = 0x02;
@@ -47,50 +86,212 @@ bitflags::bitflags! {
         const MEDIA_ENCRYPTION  = 0x08;
         const MBR_ENABLED       = 0x10;
         const MBR_DONE          = 0x20;
+        const MBR_SHADOWING_NOT_SUPPORTED = 0x40;
     }
 }
 
-#[derive(Debug)]
+/// Security Subsystem Class a drive is driven with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ssc {
+    Enterprise,
+    OpalV1,
+    OpalV2,
+    /// locking without media encryption
+    PyriteV1,
+    PyriteV2,
+    Ruby,
+}
+
+/// Decoded Level 0 discovery response. Every feature descriptor the drive didn't report is `None`.
+#[derive(Debug, Default, Clone)]
 pub struct SecureDeviceInfo {
+    pub tper: Option<TperFlags>,
     pub locking: Option<LockingFlags>,
-    pub opal_v2: Option<ComIdInfo>,
-    pub enterprise: Option<ComIdInfo>,
+    pub geometry: Option<GeometryInfo>,
+    pub enterprise: Option<SscInfo>,
+    pub opal_v1: Option<SscInfo>,
+    pub single_user_mode: Option<SingleUserModeInfo>,
+    pub datastore: Option<DataStoreInfo>,
+    pub opal_v2: Option<SscInfo>,
+    pub pyrite_v1: Option<SscInfo>,
+    pub pyrite_v2: Option<SscInfo>,
+    pub ruby: Option<SscInfo>,
+    pub block_sid: Option<BlockSidInfo>,
 }
 
-#[derive(Debug)]
-pub struct ComIdInfo {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeometryInfo {
+    /// whether the drive requires locking ranges to be aligned
+    pub align: bool,
+    pub logical_block_size: u32,
+    /// in logical blocks
+    pub alignment_granularity: u64,
+    pub lowest_aligned_lba: u64,
+}
+
+/// Common part of the Enterprise, Opal, Pyrite and Ruby SSC descriptors.
+/// Fields an SSC doesn't define are left at their defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SscInfo {
     pub base_com_id: u16,
     pub num_com_ids: u16,
+    pub range_crossing: bool,
+    pub num_locking_admins: u16,
+    pub num_locking_users: u16,
+    pub initial_pin: PinIndicator,
+    pub revert_pin: PinIndicator,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SingleUserModeInfo {
+    pub num_locking_objects: u32,
+    /// every locking object is in single user mode
+    pub all: bool,
+    /// at least one locking object is in single user mode
+    pub any: bool,
+    /// the RangeStartRangeLengthPolicy: only the admins may set RangeStart / RangeLength of the single
+    /// user ranges (`true`), or their users as well (`false`)
+    pub policy: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataStoreInfo {
+    pub max_tables: u16,
+    /// in bytes, summed over all tables
+    pub max_size: u32,
+    /// table sizes must be multiples of this
+    pub alignment: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSidInfo {
+    /// C_PIN_SID differs from C_PIN_MSID
+    pub sid_value_changed: bool,
+    pub sid_blocked: bool,
+    /// Block SID is cleared by hardware resets in addition to power cycles
+    pub hardware_reset: bool,
 }
 
 pub struct SecureDevice<P> {
     device: P,
     com_id: u16,
-    is_eprise: bool,
-    was_locked: bool,
+    ssc: Ssc,
+    info: SecureDeviceInfo,
+    properties: Properties,
+}
+
+/// Communication limits, as exchanged with the Properties method of the Session Manager
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Properties {
+    pub max_com_packet_size: u32,
+    pub max_packet_size: u32,
+    pub max_ind_token_size: u32,
+    pub max_packets: u32,
+    pub max_subpackets: u32,
+    pub max_methods: u32,
+}
+
+impl Properties {
+    /// What we support: a single method per ComPacket, but large ones
+    pub const HOST: Properties = Properties {
+        max_com_packet_size: 0x10000,
+        max_packet_size: 0x10000 - 20,
+        max_ind_token_size: 0x10000 - 20 - 24 - 12,
+        max_packets: 1,
+        max_subpackets: 1,
+        max_methods: 1,
+    };
+
+    /// The minimum every TPer has to support, used until Properties were exchanged
+    pub const MINIMUM: Properties = Properties {
+        max_com_packet_size: 2048,
+        max_packet_size: 2028,
+        max_ind_token_size: 1992,
+        max_packets: 1,
+        max_subpackets: 1,
+        max_methods: 1,
+    };
+
+    /// The smaller limit of both sides
+    pub fn min(self, other: Properties) -> Properties {
+        Properties {
+            max_com_packet_size: self.max_com_packet_size.min(other.max_com_packet_size),
+            max_packet_size: self.max_packet_size.min(other.max_packet_size),
+            max_ind_token_size: self.max_ind_token_size.min(other.max_ind_token_size),
+            max_packets: self.max_packets.min(other.max_packets),
+            max_subpackets: self.max_subpackets.min(other.max_subpackets),
+            max_methods: self.max_methods.min(other.max_methods),
+        }
+    }
+
+    /// Largest token payload of a single-subpacket ComPacket
+    pub fn max_payload(&self) -> usize {
+        let from_com_packet = (self.max_com_packet_size as usize).saturating_sub(20 + 24 + 12);
+        let from_packet = (self.max_packet_size as usize).saturating_sub(24 + 12);
+        from_com_packet.min(from_packet)
+    }
+
+    /// Largest byte string that can be written with a single Set, e.g. of the MBR or DataStore
+    /// table; leaves room for the method call around it and the long atom header.
+    pub fn max_set_bytes(&self) -> usize {
+        const SET_OVERHEAD: usize = 64;
+        let max_token = (self.max_ind_token_size as usize).saturating_sub(4);
+        self.max_payload().saturating_sub(SET_OVERHEAD).min(max_token)
+    }
+
+    /// Largest byte string a single Get can return, e.g. of the DataStore table; the response only
+    /// wraps it in the result list and the method status: `[ bytes ] EOD [ 0 0 0 ]`.
+    pub fn max_get_bytes(&self) -> usize {
+        // the tokens around the bytes and the long atom header
+        const GET_OVERHEAD: usize = 12;
+        let max_token = (self.max_ind_token_size as usize).saturating_sub(4);
+        self.max_payload().saturating_sub(GET_OVERHEAD).min(max_token)
+    }
 }
 
 impl<P: SecureProtocol> SecureDevice<P> {
     pub fn new(mut device: P) -> crate::Result<Self, P::Error> {
         let info = recv_info(&mut device)?;
         tracing::debug!(?info);
-        let is_eprise = info.enterprise.is_some();
-        let com_id = match info.enterprise.or(info.opal_v2) {
-            Some(x) => x,
+        // in order of preference, e.g. Opal 1.0 only if the drive doesn't speak Opal 2.0 as well
+        let sscs = [
+            (Ssc::Enterprise, &info.enterprise),
+            (Ssc::OpalV2, &info.opal_v2),
+            (Ssc::Ruby, &info.ruby),
+            (Ssc::PyriteV2, &info.pyrite_v2),
+            (Ssc::PyriteV1, &info.pyrite_v1),
+            (Ssc::OpalV1, &info.opal_v1),
+        ];
+        let (ssc, com_id) = match sscs.into_iter().find_map(|(ssc, info)| Some((ssc, info.as_ref()?.base_com_id))) {
+            Some(ssc) => ssc,
             None => super::UnsupportedSnafu.fail()?,
-        }
-        .base_com_id;
+        };
         Ok(Self {
             device,
             com_id,
-            is_eprise,
-            was_locked: info.locking.map_or(false, |l| l.contains(LockingFlags::LOCKED)),
+            ssc,
+            info,
+            properties: Properties::MINIMUM,
         })
     }
 
+    /// Level 0 discovery as of the SecureDevice's creation
+    pub fn info(&self) -> &SecureDeviceInfo {
+        &self.info
+    }
+
     /// whether the SecureDevice was locked upon it's creation
     pub fn was_locked(&self) -> bool {
-        self.was_locked
+        self.info.locking.is_some_and(|l| l.contains(LockingFlags::LOCKED))
+    }
+
+    /// Negotiated communication limits, [`Properties::MINIMUM`] until they were exchanged
+    pub fn properties(&self) -> Properties {
+        self.properties
+    }
+
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
     }
 
     pub fn reconnect_controller(&mut self) -> crate::Result<(), P::Error> {
@@ -102,30 +303,109 @@ impl<P: SecureProtocol> SecureDevice<P> {
         self.com_id
     }
 
+    pub fn ssc(&self) -> Ssc {
+        self.ssc
+    }
+
     pub fn is_eprise(&self) -> bool {
-        self.is_eprise
+        self.ssc == Ssc::Enterprise
+    }
+
+    /// Opal 1.0 drive, which may lack e.g. the Authenticate method
+    pub fn is_opal_v1(&self) -> bool {
+        self.ssc == Ssc::OpalV1
+    }
+
+    /// Whether the drive encrypts its data; Pyrite drives only lock access to it
+    pub fn media_encryption(&self) -> bool {
+        self.info.locking.is_some_and(|l| l.contains(LockingFlags::MEDIA_ENCRYPTION))
+    }
+
+    /// Whether the drive has an MBR shadow, Enterprise and some Ruby drives don't
+    pub fn mbr_shadowing(&self) -> bool {
+        !self.info.locking.is_some_and(|l| l.contains(LockingFlags::MBR_SHADOWING_NOT_SUPPORTED))
     }
 
     pub fn proto(&mut self) -> &mut P {
         &mut self.device
     }
 
+    /// Resets the stack of our ComID (STACK_RESET of ComID management): sessions left open on it, e.g.
+    /// by a crash in the middle of an unlock, are aborted and pending responses discarded.
+    pub fn stack_reset(&mut self) -> crate::Result<(), P::Error> {
+        let com_id = self.com_id;
+        let mut request = crate::util::alloc_aligned(512, self.device.align());
+        request[0..2].copy_from_slice(&com_id.to_be_bytes());
+        request[4..8].copy_from_slice(&STACK_RESET.to_be_bytes());
+        unsafe { self.device.secure_send(2, com_id, request.as_mut()) }.context(super::IoSnafu)?;
+
+        let mut response = crate::util::alloc_aligned(512, self.device.align());
+        for _ in 0..MAX_POLLS {
+            unsafe { self.device.secure_recv(2, com_id, response.as_mut()) }.context(super::IoSnafu)?;
+            // ComID, ComID extension, request code, reserved, available data length, status
+            let available = u16::from_be_bytes([response[10], response[11]]);
+            if available == 0 {
+                // not processed yet
+                self.device.stall(POLL_INTERVAL_US);
+                continue;
+            }
+            if response[0..2] != com_id.to_be_bytes() || response[4..8] != STACK_RESET.to_be_bytes() || available < 4 {
+                return Err(super::Error::Opal { source: OpalError::MalformedComPacket, msg: "malformed STACK_RESET response".into() });
+            }
+            return match u32::from_be_bytes(response[12..16].try_into().unwrap()) {
+                0 => Ok(()),
+                _ => Err(super::Error::Opal { source: OpalError::StackResetFailed, msg: format!("STACK_RESET of ComID {com_id:#x} failed") }),
+            };
+        }
+        Err(super::Error::Opal { source: OpalError::StackResetFailed, msg: format!("no response to STACK_RESET of ComID {com_id:#x}") })
+    }
+
+    /// Asks the TPer for a dynamically allocated ComID (GET_COMID)
+    pub fn get_comid(&mut self) -> crate::Result<u16, P::Error> {
+        let mut response = crate::util::alloc_aligned(512, self.device.align());
+        unsafe { self.device.secure_recv(2, 0, response.as_mut()) }.context(super::IoSnafu)?;
+        Ok(u16::from_be_bytes([response[0], response[1]]))
+    }
+
+    /// Programmatic reset of the TPer (TPER_RESET): all sessions are aborted and LockOnReset
+    /// is applied to ranges that include programmatic resets. The TPer forgets the host properties,
+    /// so the properties fall back to [`Properties::MINIMUM`] until they are exchanged again.
+    pub fn tper_reset(&mut self) -> crate::Result<(), P::Error> {
+        let mut request = crate::util::alloc_aligned(512, self.device.align());
+        unsafe { self.device.secure_send(2, 4, request.as_mut()) }.context(super::IoSnafu)?;
+        self.properties = Properties::MINIMUM;
+        Ok(())
+    }
+
+    /// Sends the Block SID Authentication command of the TCG Storage Feature Set,
+    /// IF-SEND with protocol 2 to ComID 5.
+    pub fn send_block_sid(&mut self, hardware_reset: bool) -> crate::Result<(), P::Error> {
+        let mut buffer = crate::util::alloc_aligned(512, self.device.align());
+        // Clear Events: also unblock on hardware resets, not only power cycles
+        buffer[0] = hardware_reset as u8;
+        unsafe { self.device.secure_send(2, 5, buffer.as_mut()) }.context(super::IoSnafu)
+    }
+
+    pub fn block_sid_info(&mut self) -> crate::Result<Option<BlockSidInfo>, P::Error> {
+        Ok(recv_info(self.proto())?.block_sid)
+    }
+
+    pub fn single_user_mode_info(&mut self) -> crate::Result<Option<SingleUserModeInfo>, P::Error> {
+        Ok(recv_info(self.proto())?.single_user_mode)
+    }
+
     pub fn recv_locked(&mut self) -> crate::Result<bool, P::Error> {
         Ok(recv_info(self.proto())?
             .locking
-            .map_or(false, |locking| {
+            .is_some_and(|locking| {
                 locking.contains(LockingFlags::LOCKED)
             }))
     }
 }
 
-/// Level 0 discovery subset
-fn recv_info<P: SecureProtocol>(proto: &mut P) -> crate::Result<SecureDeviceInfo, P::Error> {
-    let mut device_info = SecureDeviceInfo {
-        locking: None,
-        opal_v2: None,
-        enterprise: None,
-    };
+/// Level 0 discovery
+pub(crate) fn recv_info<P: SecureProtocol>(proto: &mut P) -> crate::Result<SecureDeviceInfo, P::Error> {
+    let mut device_info = SecureDeviceInfo::default();
 
     let mut buffer = crate::util::alloc_aligned(1024, proto.align());
 
@@ -137,36 +417,124 @@ fn recv_info<P: SecureProtocol>(proto: &mut P) -> crate::Result<SecureDeviceInfo
         return Err(crate::Error::IncompatibleVersion);
     }
 
+    // the length field doesn't include itself
+    let len = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize;
+    let end = len.saturating_add(4).min(buffer.len());
+
     // ignore the rest of the header
     let mut offset = 48;
 
-    fn get_com_id(buffer: &[u8], offset: usize) -> ComIdInfo {
-        ComIdInfo {
-            base_com_id: (buffer[offset] as u16) << 8 | buffer[offset + 1] as u16,
-            num_com_ids: (buffer[offset + 2] as u16) << 8 | buffer[offset + 3] as u16,
+    while let Some(header) = buffer.get(offset..offset + 4).filter(|_| offset + 4 <= end) {
+        let code = FeatureCodes(u16::from_be_bytes([header[0], header[1]]));
+        let len = header[3] as usize;
+        let data = Descriptor(&buffer[offset + 4..(offset + 4 + len).min(end)]);
+        match code {
+            FeatureCodes::TPER => device_info.tper = data.u8(0).map(TperFlags::from_bits_retain),
+            FeatureCodes::LOCKING => device_info.locking = data.u8(0).map(LockingFlags::from_bits_retain),
+            FeatureCodes::GEOMETRY => device_info.geometry = data.geometry(),
+            FeatureCodes::ENTERPRISE => device_info.enterprise = data.ssc(false),
+            FeatureCodes::OPAL_V1 => device_info.opal_v1 = data.ssc(false),
+            FeatureCodes::SINGLE_USER => device_info.single_user_mode = data.single_user_mode(),
+            FeatureCodes::DATASTORE => device_info.datastore = data.datastore(),
+            FeatureCodes::OPAL_V2 => device_info.opal_v2 = data.ssc(true),
+            FeatureCodes::PYRITE_V1 => device_info.pyrite_v1 = data.pyrite(),
+            FeatureCodes::PYRITE_V2 => device_info.pyrite_v2 = data.pyrite(),
+            FeatureCodes::RUBY => device_info.ruby = data.ssc(true),
+            FeatureCodes::BLOCK_SID => device_info.block_sid = data.block_sid(),
+            _ => tracing::trace!("skipping unknown feature descriptor {code:?}"),
         }
+        offset += len + 4;
     }
 
-    while offset < buffer.len() - 1 {
-        match FeatureCodes((buffer[offset] as u16) << 8 | buffer[offset + 1] as u16) {
-            FeatureCodes::LOCKING => {
-                device_info.locking = LockingFlags::from_bits(match buffer.get(offset + 4) {
-                    Some(&bits) => bits,
-                    None => break,
-                })
-            }
-            FeatureCodes::ENTERPRISE => {
-                device_info.enterprise = Some(get_com_id(&buffer, offset + 4));
-            }
-            FeatureCodes::OPAL_V2 => device_info.opal_v2 = Some(get_com_id(&buffer, offset + 4)),
-            _ => {}
-        }
-        let len = match buffer.get(offset + 3) {
-            Some(&len) => len as usize,
-            None => break,
+    Ok(device_info)
+}
+
+/// Feature descriptor data without the 4-byte header. All getters return `None` if the
+/// descriptor is too short, in which case the whole descriptor is ignored.
+struct Descriptor<'a>(&'a [u8]);
+
+impl<'a> Descriptor<'a> {
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.0.get(offset).copied()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(self.0.get(offset..offset + 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_be_bytes(self.0.get(offset..offset + 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        Some(u64::from_be_bytes(self.0.get(offset..offset + 8)?.try_into().unwrap()))
+    }
+
+    fn geometry(&self) -> Option<GeometryInfo> {
+        Some(GeometryInfo {
+            align: self.u8(0)? & 0x01 != 0,
+            logical_block_size: self.u32(8)?,
+            alignment_granularity: self.u64(12)?,
+            lowest_aligned_lba: self.u64(20)?,
+        })
+    }
+
+    /// Enterprise and Opal v1 only define the ComIDs and range crossing,
+    /// Opal v2 and Ruby additionally the authority counts and PIN indicators.
+    fn ssc(&self, v2: bool) -> Option<SscInfo> {
+        let mut info = SscInfo {
+            base_com_id: self.u16(0)?,
+            num_com_ids: self.u16(2)?,
+            range_crossing: self.u8(4)? & 0x01 != 0,
+            num_locking_admins: 0,
+            num_locking_users: 0,
+            initial_pin: PinIndicator::MSID,
+            revert_pin: PinIndicator::MSID,
         };
-        offset += len + 4;
+        if v2 {
+            info.num_locking_admins = self.u16(5)?;
+            info.num_locking_users = self.u16(7)?;
+            info.initial_pin = PinIndicator(self.u8(9)?);
+            info.revert_pin = PinIndicator(self.u8(10)?);
+        }
+        Some(info)
     }
 
-    Ok(device_info)
+    fn pyrite(&self) -> Option<SscInfo> {
+        Some(SscInfo {
+            base_com_id: self.u16(0)?,
+            num_com_ids: self.u16(2)?,
+            range_crossing: false,
+            num_locking_admins: 0,
+            num_locking_users: 0,
+            initial_pin: PinIndicator(self.u8(9)?),
+            revert_pin: PinIndicator(self.u8(10)?),
+        })
+    }
+
+    fn single_user_mode(&self) -> Option<SingleUserModeInfo> {
+        let flags = self.u8(4)?;
+        Some(SingleUserModeInfo {
+            num_locking_objects: self.u32(0)?,
+            any: flags & 0x01 != 0,
+            all: flags & 0x02 != 0,
+            policy: flags & 0x04 != 0,
+        })
+    }
+
+    fn datastore(&self) -> Option<DataStoreInfo> {
+        Some(DataStoreInfo {
+            max_tables: self.u16(2)?,
+            max_size: self.u32(4)?,
+            alignment: self.u32(8)?,
+        })
+    }
+
+    fn block_sid(&self) -> Option<BlockSidInfo> {
+        Some(BlockSidInfo {
+            sid_value_changed: self.u8(0)? & 0x01 != 0,
+            sid_blocked: self.u8(0)? & 0x02 != 0,
+            hardware_reset: self.u8(1)? & 0x01 != 0,
+        })
+    }
 }
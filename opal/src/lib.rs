@@ -5,9 +5,8 @@ extern crate alloc;
 
 use alloc::fmt::{Debug, Display};
 use alloc::string::String;
-use defs::uid;
+use alloc::vec::Vec;
 use io::SecureDevice;
-use session::OpalSession;
 use snafu::{Snafu, Location, AsErrorSource, OptionExt, ensure};
 
 mod defs;
@@ -15,8 +14,15 @@ mod util;
 mod io;
 mod command;
 mod session;
+mod pin;
+pub mod table;
+#[cfg(any(test, feature = "simulator"))]
+pub mod sim;
 
-pub use defs::{OpalError, StatusCode};
+pub use defs::{OpalError, StatusCode, LockingState, Authority, BS8, uid};
+pub use session::OpalSession;
+pub use command::Value;
+pub use pin::{PinHash, Prf, Salt};
 #[derive(Debug, Snafu)]
 pub enum Error<E: Debug + Display + AsErrorSource> {
     Io { source: E, location: Location },
@@ -24,18 +30,27 @@ pub enum Error<E: Debug + Display + AsErrorSource> {
     IncompatibleVersion,
     Pbkdf,
     RawKeyInvalidLength,
+    /// wrong password of the authority; NOT_AUTHORIZED of a method after that means it may not call it
+    Authentication { authority: BS8 },
+    /// the authority may not make the locking range read-only, it can't enable WriteLockEnabled
+    WriteLockDisabled { locking_range: u8 },
     Opal { source: OpalError, msg: String },
 }
 type Result<O, E> = core::result::Result<O, Error<E>>;
 
-pub use io::SecureProtocol;
+pub use io::{
+    SecureProtocol, SecureDeviceInfo, TperFlags, LockingFlags, GeometryInfo, SscInfo, PinIndicator,
+    SingleUserModeInfo, DataStoreInfo, BlockSidInfo, Properties, Ssc,
+};
 
 pub struct OpalDrive<P> {
     dev: SecureDevice<P>,
 }
 impl<P: SecureProtocol> OpalDrive<P> {
+    /// Connects to the drive: Level 0 discovery followed by the Properties exchange.
     pub fn new(p: P) -> Result<Self, P::Error> {
-        let dev = io::SecureDevice::new(p)?;
+        let mut dev = io::SecureDevice::new(p)?;
+        exchange_properties(&mut dev);
         Ok(Self { dev })
     }
 
@@ -43,43 +58,362 @@ impl<P: SecureProtocol> OpalDrive<P> {
         self.dev.proto().serial_num()
     }
 
+    /// Level 0 discovery of the drive, as received when connecting to it
+    pub fn info(&self) -> &SecureDeviceInfo {
+        self.dev.info()
+    }
+
     pub fn was_locked(&self) -> bool {
         self.dev.was_locked()
     }
 
+    /// The Security Subsystem Class the drive is driven with
+    pub fn ssc(&self) -> Ssc {
+        self.dev.ssc()
+    }
+
+    /// Whether the drive encrypts its data. Pyrite drives don't, locking only guards access to the
+    /// data, which can still be read by taking the media out of the drive.
+    pub fn media_encryption(&self) -> bool {
+        self.dev.media_encryption()
+    }
+
+    /// Communication limits negotiated with the drive
+    pub fn properties(&self) -> Properties {
+        self.dev.properties()
+    }
+
+    /// whether the drive currently reports being locked, as opposed to [`Self::was_locked`]
+    pub fn is_locked(&mut self) -> Result<bool, P::Error> {
+        self.dev.recv_locked()
+    }
+
+    /// Unlocks the global range, authenticating as Admin1 (BandMaster0 on Enterprise drives).
     pub fn unlock(&mut self, pwd: PasswordOrRaw) -> Result<(), P::Error> {
-        let mut hash = alloc::vec![0; 32];
-
-        match pwd {
-            PasswordOrRaw::Password(pwd) => {
-                pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(
-                    pwd,
-                    self.dev.proto().serial_num(),
-                    75000,
-                    &mut hash,
-                ).ok().context(PbkdfSnafu)?;
+        self.unlock_ranges(pwd, Authority::default(), &[0])
+    }
+
+    /// Unlocks the given locking ranges with a single credential, leaving all other ranges as they are.
+    ///
+    /// Opal drives authenticate as `authority` once and set Done on the MBR shadow if the global range
+    /// is among `ranges`. Users need to be granted that by an admin, otherwise the shadow stays active.
+    /// Enterprise drives have no Admin / User authorities and authenticate as the BandMaster of each band.
+    pub fn unlock_ranges(&mut self, pwd: PasswordOrRaw, authority: Authority, ranges: &[u8]) -> Result<(), P::Error> {
+        self.set_ranges(pwd, authority, ranges, LockingState::ReadWrite)
+    }
+
+    /// Locks the given locking ranges again like [`Self::unlock_ranges`] unlocks them,
+    /// re-enabling the MBR shadow if the global range is among them.
+    pub fn lock_ranges(&mut self, pwd: PasswordOrRaw, authority: Authority, ranges: &[u8]) -> Result<(), P::Error> {
+        self.set_ranges(pwd, authority, ranges, LockingState::Locked)
+    }
+
+    /// Sets the given locking ranges to `state`, see [`Self::unlock_ranges`]. MBR Done is cleared
+    /// when locking the global range and set otherwise.
+    pub fn set_ranges(&mut self, pwd: PasswordOrRaw, authority: Authority, ranges: &[u8], state: LockingState) -> Result<(), P::Error> {
+        let hash = self.hash(pwd)?;
+
+        if self.dev.is_eprise() {
+            for &band in ranges {
+                let mut session = OpalSession::start(&mut self.dev, uid::ENTERPRISE_LOCKINGSP, uid::band_master(band), Some(&hash))?;
+                match state {
+                    LockingState::ReadOnly => session.set_locking_range_read_only(band)?,
+                    state => session.set_locking_range(band, state)?,
+                }
             }
-            PasswordOrRaw::Raw(r) => {
-                ensure!(r.len() == hash.len(), RawKeyInvalidLengthSnafu);
-                hash.copy_from_slice(r);
+        } else {
+            let mbr_shadowing = self.dev.mbr_shadowing();
+            let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(&hash))?;
+            for &range in ranges {
+                match state {
+                    LockingState::ReadOnly => session.set_locking_range_read_only(range)?,
+                    state => session.set_locking_range(range, state)?,
+                }
+            }
+            if ranges.contains(&0) && mbr_shadowing {
+                match (session.set_mbr_done(state != LockingState::Locked), authority) {
+                    (Err(Error::Opal { source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED }, .. }), Authority::User(n)) => {
+                        tracing::warn!("User{n} may not set MBRControl Done, the shadow MBR is left as it is");
+                    }
+                    (res, _) => res?,
+                }
             }
         }
 
-        tracing::info!("{hash:x?}");
+        self.dev.reconnect_controller()?;
 
-        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(&hash))?;
-        session.set_locking_range(0, defs::LockingState::ReadWrite)?;
-        session.set_mbr_done(true)?;
+        Ok(())
+    }
 
+    /// Changes the password of `authority`, authenticating with its old password.
+    /// Both are hashed the same way as for unlocking.
+    pub fn change_password(&mut self, authority: Authority, old: PasswordOrRaw, new: PasswordOrRaw) -> Result<(), P::Error> {
+        let old = self.hash(old)?;
+        let new = self.hash(new)?;
+        self.change_pin(authority, &old, &new)
+    }
+
+    fn change_pin(&mut self, authority: Authority, old: &[u8], new: &[u8]) -> Result<(), P::Error> {
+        let mut session = OpalSession::start(&mut self.dev, authority.sp(), authority.uid(), Some(old))?;
+        session.set_pin(authority.c_pin(), new)
+    }
+
+    /// Number of password attempts `authority` has left before it's locked out, read from the
+    /// Tries / TryLimit columns of its C_PIN row in an anonymous session.
+    ///
+    /// `None` if there is no limit or the TPer doesn't let Anybody read them, which the Opal SSC doesn't
+    /// require. Always `None` on Enterprise drives.
+    pub fn remaining_tries(&mut self, authority: Authority) -> Result<Option<u32>, P::Error> {
+        if self.dev.is_eprise() {
+            return Ok(None);
+        }
+        let row = OpalSession::start(&mut self.dev, authority.sp(), uid::OPAL_ANYBODY, None)
+            .and_then(|mut session| session.get(authority.c_pin(), table::c_pin::TRY_LIMIT, table::c_pin::TRIES));
+        let row = match row {
+            Ok(row) => row,
+            Err(Error::Opal { source: OpalError::Status { code }, .. }) => {
+                tracing::debug!("can't read the tries of {authority:?}: {code:?}");
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let try_limit = row.get(table::c_pin::TRY_LIMIT).and_then(Value::as_uint);
+        let tries = row.get(table::c_pin::TRIES).and_then(Value::as_uint);
+        match (try_limit, tries) {
+            (Some(0), _) => Ok(None),
+            (Some(try_limit), Some(tries)) => Ok(Some(try_limit.saturating_sub(tries) as u32)),
+            _ => Err(Error::Opal { source: OpalError::MalformedResponse, msg: "Get of C_PIN returned no Tries / TryLimit".into() }),
+        }
+    }
+
+    /// Reads `len` bytes at `offset` of the DataStore table, authenticating as `authority` in the Locking SP.
+    pub fn read_datastore(&mut self, pwd: PasswordOrRaw, authority: Authority, offset: u32, len: usize) -> Result<Vec<u8>, P::Error> {
+        ensure!(!self.dev.is_eprise(), UnsupportedSnafu);
+        let hash = self.hash(pwd)?;
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(&hash))?;
+        session.read_datastore(offset, len)
+    }
+
+    /// Writes `data` at `offset` of the DataStore table, authenticating as `authority` in the Locking SP.
+    pub fn write_datastore(&mut self, pwd: PasswordOrRaw, authority: Authority, offset: u32, data: &[u8]) -> Result<(), P::Error> {
+        ensure!(!self.dev.is_eprise(), UnsupportedSnafu);
+        let hash = self.hash(pwd)?;
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(&hash))?;
+        session.write_datastore(offset, data)
+    }
+
+    /// Cryptographically erases the given locking ranges; all data in them is lost.
+    ///
+    /// Opal drives authenticate as `authority` once, which needs to be an admin. Enterprise drives
+    /// authenticate as EraseMaster instead, which also resets the erased bands to their factory state.
+    pub fn erase_ranges(&mut self, pwd: PasswordOrRaw, authority: Authority, ranges: &[u8]) -> Result<(), P::Error> {
+        // there are no keys to regenerate
+        ensure!(self.dev.media_encryption(), UnsupportedSnafu);
+        let hash = self.hash(pwd)?;
+        let (sp, authority) = match self.dev.is_eprise() {
+            true => (uid::ENTERPRISE_LOCKINGSP, uid::ENTERPRISE_ERASEMASTER),
+            false => (uid::OPAL_LOCKINGSP, authority.uid()),
+        };
+        let mut session = OpalSession::start(&mut self.dev, sp, authority, Some(&hash))?;
+        for &range in ranges {
+            session.erase_locking_range(range)?;
+        }
         drop(session);
-        self.dev.reconnect_controller()?;
 
+        // the drive's content changed under the feet of the OS
+        self.dev.reconnect_controller()
+    }
+
+    /// Reads the MSID, the password SID (and Admin1 after activation) has in factory state.
+    pub fn msid(&mut self) -> Result<Vec<u8>, P::Error> {
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None)?;
+        let row = session.get(uid::OPAL_C_PIN_MSID, table::c_pin::PIN, table::c_pin::PIN)?;
+        match row.get(table::c_pin::PIN).and_then(Value::as_bytes) {
+            Some(msid) => Ok(msid.to_vec()),
+            None => Err(Error::Opal { source: OpalError::MalformedResponse, msg: "Get of MSID returned no PIN".into() }),
+        }
+    }
+
+    /// Takes ownership of a drive in factory state by changing the password of SID from MSID to `sid`.
+    pub fn take_ownership(&mut self, sid: PasswordOrRaw) -> Result<(), P::Error> {
+        let sid = self.hash(sid)?;
+        let msid = self.msid()?;
+        self.change_pin(Authority::Sid, &msid, &sid)
+    }
+
+    /// Activates the Locking SP as SID, which gives Admin1 the password of SID.
+    /// The Locking SP of Enterprise drives is always active.
+    pub fn activate_locking_sp(&mut self, sid: PasswordOrRaw) -> Result<(), P::Error> {
+        ensure!(!self.dev.is_eprise(), UnsupportedSnafu);
+        let sid = self.hash(sid)?;
+        self.activate_locking_sp_pin(&sid)
+    }
+
+    fn activate_locking_sp_pin(&mut self, sid: &[u8]) -> Result<(), P::Error> {
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_SID, Some(sid))?;
+        session.activate(uid::OPAL_LOCKINGSP)
+    }
+
+    /// Activates the Locking SP as SID like [`Self::activate_locking_sp`], putting `ranges` into Single
+    /// User Mode: each of them is owned by its own user (see [`Authority::single_user`]) whose password
+    /// starts out empty (see [`Self::set_single_user_password`]), and the admins can no longer lock or unlock them. `admins_only` keeps setting
+    /// RangeStart / RangeLength to the admins, otherwise those users may as well. Needs the Single User Mode feature.
+    pub fn activate_locking_sp_single_user(&mut self, sid: PasswordOrRaw, ranges: &[u8], admins_only: bool) -> Result<(), P::Error> {
+        ensure!(!self.dev.is_eprise() && self.dev.info().single_user_mode.is_some(), UnsupportedSnafu);
+        let sid = self.hash(sid)?;
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_SID, Some(&sid))?;
+        session.activate_single_user(uid::OPAL_LOCKINGSP, ranges, admins_only)
+    }
+
+    /// Single User Mode state from a fresh Level 0 discovery, `None` if the drive doesn't support it
+    pub fn single_user_mode(&mut self) -> Result<Option<SingleUserModeInfo>, P::Error> {
+        self.dev.single_user_mode_info()
+    }
+
+    /// Sets the first password of the user owning Single User Mode range `range`, which is empty
+    /// after activation. Later changes go through [`Self::change_password`].
+    pub fn set_single_user_password(&mut self, range: u8, new: PasswordOrRaw) -> Result<(), P::Error> {
+        let authority = Authority::single_user(range);
+        let new = self.hash(new)?;
+        let mut session = OpalSession::start(&mut self.dev, authority.sp(), authority.uid(), Some(&[]))?;
+        session.set_pin(authority.c_pin(), &new)
+    }
+
+    /// Unlocks locking range `range` in Single User Mode as its own user, see [`Authority::single_user`].
+    pub fn unlock_single_user_range(&mut self, pwd: PasswordOrRaw, range: u8) -> Result<(), P::Error> {
+        self.unlock_ranges(pwd, Authority::single_user(range), &[range])
+    }
+
+    /// Enables read and write locking of the global range and locks it on power cycles (LockOnReset),
+    /// authenticating as Admin1 (BandMaster0 on Enterprise drives). The range stays unlocked until then.
+    pub fn enable_global_locking(&mut self, admin1: PasswordOrRaw) -> Result<(), P::Error> {
+        let admin1 = self.hash(admin1)?;
+        self.enable_global_locking_pin(&admin1)
+    }
+
+    fn enable_global_locking_pin(&mut self, admin1: &[u8]) -> Result<(), P::Error> {
+        let (sp, authority, object) = match self.dev.is_eprise() {
+            true => (uid::ENTERPRISE_LOCKINGSP, uid::band_master(0), uid::band(0)),
+            false => (uid::OPAL_LOCKINGSP, Authority::default().uid(), uid::locking_range(0)),
+        };
+        let mut session = OpalSession::start(&mut self.dev, sp, authority, Some(admin1))?;
+        session.set(object, &[
+            (table::locking::READ_LOCK_ENABLED, true.into()),
+            (table::locking::WRITE_LOCK_ENABLED, true.into()),
+            // [ Power Cycle ]
+            (table::locking::LOCK_ON_RESET, Value::List(alloc::vec![Value::Uint(0)])),
+        ])
+    }
+
+    /// Provisions a drive in factory state like `sedutil-cli --initialsetup` without the MBR shadow:
+    /// takes ownership, activates the Locking SP, sets the password of Admin1 and enables global range locking.
+    pub fn initial_setup(&mut self, sid: PasswordOrRaw, admin1: PasswordOrRaw) -> Result<(), P::Error> {
+        ensure!(!self.dev.is_eprise(), UnsupportedSnafu);
+        // hash only once, every step authenticates anew
+        let sid = self.hash(sid)?;
+        let admin1 = self.hash(admin1)?;
+        let msid = self.msid()?;
+        self.change_pin(Authority::Sid, &msid, &sid)?;
+        self.activate_locking_sp_pin(&sid)?;
+        self.change_pin(Authority::Admin(1), &sid, &admin1)?;
+        self.enable_global_locking_pin(&admin1)
+    }
+
+    /// Reverts the whole TPer to factory state as SID: SID gets MSID again and the Locking SP is
+    /// deactivated, which erases all data on the drive.
+    pub fn revert_tper(&mut self, sid: PasswordOrRaw) -> Result<(), P::Error> {
+        let sid = self.hash(sid)?;
+        let session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_SID, Some(&sid))?;
+        session.revert(uid::OPAL_ADMINSP)?;
+        self.dev.reconnect_controller()
+    }
+
+    /// Reverts the whole TPer to factory state as PSID, the way to recover a drive whose passwords are lost.
+    /// The PSID printed on the drive label is used as is, it's not hashed. Erases all data on the drive.
+    pub fn psid_revert(&mut self, psid: &[u8]) -> Result<(), P::Error> {
+        let session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_PSID, Some(psid))?;
+        session.revert(uid::OPAL_ADMINSP)?;
+        self.dev.reconnect_controller()
+    }
+
+    /// Reverts the Locking SP to factory state as Admin1, which erases all data on the drive
+    /// but keeps SID. The Locking SP is inactive afterwards.
+    pub fn revert_locking_sp(&mut self, admin1: PasswordOrRaw) -> Result<(), P::Error> {
+        ensure!(!self.dev.is_eprise(), UnsupportedSnafu);
+        let admin1 = self.hash(admin1)?;
+        let session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, Authority::default().uid(), Some(&admin1))?;
+        session.revert_sp()?;
+        self.dev.reconnect_controller()
+    }
+
+    /// `count` bytes from the random number generator of the TPer, read in an anonymous Admin SP session.
+    pub fn random(&mut self, count: usize) -> Result<Vec<u8>, P::Error> {
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None)?;
+        session.random(count)
+    }
+
+    /// Aborts all sessions on our ComID and discards pending responses, see [`SecureDevice::stack_reset`].
+    pub fn stack_reset(&mut self) -> Result<(), P::Error> {
+        self.dev.stack_reset()
+    }
+
+    /// Programmatic reset of the TPer, aborting all sessions. The properties are exchanged again afterwards.
+    pub fn tper_reset(&mut self) -> Result<(), P::Error> {
+        self.dev.tper_reset()?;
+        exchange_properties(&mut self.dev);
         Ok(())
     }
+
+    /// Blocks authentication as SID until the next power cycle, or also the next hardware reset if
+    /// `hardware_reset` is set, so that the OS can neither take ownership of a drive whose SID is still
+    /// MSID nor brute-force SID. Needs the Block SID feature in Level 0 discovery.
+    pub fn block_sid(&mut self, hardware_reset: bool) -> Result<(), P::Error> {
+        ensure!(self.dev.info().block_sid.is_some(), UnsupportedSnafu);
+        self.dev.send_block_sid(hardware_reset)?;
+        match self.dev.block_sid_info()? {
+            Some(info) if info.sid_blocked => Ok(()),
+            _ => Err(Error::Opal { source: OpalError::MalformedResponse, msg: "SID is not blocked after Block SID".into() }),
+        }
+    }
+
+    /// Starts a session to `sp`, authenticating as `authority` if a password is given.
+    pub fn start_session(
+        &mut self,
+        sp: BS8,
+        authority: BS8,
+        pwd: Option<PasswordOrRaw>,
+    ) -> Result<OpalSession<'_, P>, P::Error> {
+        let hash = pwd.map(|pwd| self.hash(pwd)).transpose()?;
+        OpalSession::start(&mut self.dev, sp, authority, hash.as_deref())
+    }
+
+    fn hash(&mut self, pwd: PasswordOrRaw) -> Result<Vec<u8>, P::Error> {
+        let hash = match pwd {
+            PasswordOrRaw::Password(pwd) => PinHash::SEDUTIL.derive(pwd, self.dev.proto().serial_num()).context(PbkdfSnafu)?,
+            PasswordOrRaw::Hashed(pwd, hash) => hash.derive(pwd, self.dev.proto().serial_num()).context(PbkdfSnafu)?,
+            PasswordOrRaw::Raw(r) => {
+                ensure!(r.len() == 32, RawKeyInvalidLengthSnafu);
+                r.to_vec()
+            }
+        };
+
+        Ok(hash)
+    }
+}
+
+/// Exchanges the Properties, falling back to the minimum limits if the TPer refuses.
+fn exchange_properties<P: SecureProtocol>(dev: &mut SecureDevice<P>) {
+    match OpalSession::exchange_properties(dev, Properties::HOST) {
+        Ok(properties) => dev.set_properties(properties),
+        Err(e) => tracing::warn!("Properties exchange failed, assuming the minimum limits: {e:?}"),
+    }
 }
 
+#[derive(Clone)]
 pub enum PasswordOrRaw<'a> {
+    /// Hashed like sedutil does, see [`PinHash::SEDUTIL`]
     Password(&'a [u8]),
     /// Must be 32 bytes
     Raw(&'a [u8]),
+    Hashed(&'a [u8], PinHash),
 }
@@ -0,0 +1,67 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use hmac::Hmac;
+
+/// How a password is turned into the PIN sent to the drive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinHash {
+    /// The password is the PIN, like with the Linux kernel's `sed-opal` ioctls or `sedutil-cli -n`
+    Plain,
+    Pbkdf2 {
+        prf: Prf,
+        iterations: u32,
+        salt: Salt,
+        /// length of the PIN in bytes
+        len: usize,
+    },
+}
+
+/// Pseudo-random function of PBKDF2
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Prf {
+    HmacSha1,
+    HmacSha256,
+    HmacSha512,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Salt {
+    /// the serial number as reported by the drive, including its padding
+    Serial,
+    None,
+    Fixed(Vec<u8>),
+}
+
+impl PinHash {
+    /// What `sedutil-cli` does: PBKDF2-HMAC-SHA1 with 75000 iterations salted with the serial number
+    pub const SEDUTIL: PinHash = PinHash::Pbkdf2 {
+        prf: Prf::HmacSha1,
+        iterations: 75000,
+        salt: Salt::Serial,
+        len: 32,
+    };
+
+    /// Derives the PIN of `password`; `None` if the parameters are invalid, e.g. an empty PIN
+    pub fn derive(&self, password: &[u8], serial: &[u8]) -> Option<Vec<u8>> {
+        let (prf, iterations, salt, len) = match self {
+            PinHash::Plain => return Some(password.to_vec()),
+            PinHash::Pbkdf2 { prf, iterations, salt, len } => (prf, *iterations, salt, *len),
+        };
+        let salt = match salt {
+            Salt::Serial => serial,
+            Salt::None => &[],
+            Salt::Fixed(salt) => salt,
+        };
+        if len == 0 || iterations == 0 {
+            return None;
+        }
+
+        let mut pin = vec![0; len];
+        match prf {
+            Prf::HmacSha1 => pbkdf2::pbkdf2::<Hmac<sha1::Sha1>>(password, salt, iterations, &mut pin),
+            Prf::HmacSha256 => pbkdf2::pbkdf2::<Hmac<sha2::Sha256>>(password, salt, iterations, &mut pin),
+            Prf::HmacSha512 => pbkdf2::pbkdf2::<Hmac<sha2::Sha512>>(password, salt, iterations, &mut pin),
+        }.ok()?;
+        Some(pin)
+    }
+}
@@ -1,13 +1,24 @@
 use alloc::format;
 use alloc::string::String;
 use alloc::borrow::ToOwned;
-use core::{fmt::Write, mem::size_of_val};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{fmt::Write, mem::{size_of, size_of_val}};
 use snafu::ResultExt;
 
 use crate::{tokens, token_list, token_name};
 use crate::defs::*;
 use crate::command::*;
-use crate::io::{SecureProtocol, SecureDevice};
+use crate::io::{Properties, SecureProtocol, SecureDevice, MAX_POLLS, POLL_INTERVAL_US};
+use crate::table::{c_pin, locking, Column, LockingRangeRow, Row};
+
+/// Largest response accepted from a TPer
+const MAX_RECV_BUFFER_LEN: usize = 1 << 20;
+
+/// Optional parameters of Activate added by the Single User Mode feature set
+const SINGLE_USER_SELECTION_LIST: u64 = 0x06_0000;
+const RANGE_START_RANGE_LENGTH_POLICY: u64 = 0x06_0001;
 
 pub struct OpalSession<'d, P: SecureProtocol> {
     device: &'d mut SecureDevice<P>,
@@ -30,93 +41,164 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
             protocol: 0x01,
         };
 
-        let challenge_tokens = match challenge {
-            Some(challenge) if !s.device.is_eprise() => {
-                tokens![
-                    token_name!(tiny_atom::UINT_00, challenge),
-                    token_name!(tiny_atom::UINT_03, sign_authority),
-                ]
-            }
-            _ => tokens![],
-        };
-
-        let command = OpalCommandBuilder::new(uid::OPAL_SMUID, method::STARTSESSION)
-            .payload(token_list![
-                105, // our HSN, same as in sedutil, although looks like it can be arbitrary
-                sp_uid,
-                tiny_atom::UINT_01,
-                challenge_tokens,
-                if s.device.is_eprise() {
-                    token_name!(b"SessionTimeout", 60000)
-                } else {
-                    tokens![]
+        let eprise = s.device.is_eprise();
+        let command = || {
+            let challenge_tokens = match challenge {
+                Some(challenge) if !eprise => {
+                    tokens![
+                        token_name!(tiny_atom::UINT_00, challenge),
+                        token_name!(tiny_atom::UINT_03, sign_authority),
+                    ]
                 }
-            ])
-            .build();
+                _ => tokens![],
+            };
+            OpalCommandBuilder::new(uid::OPAL_SMUID, method::STARTSESSION)
+                .payload(token_list![
+                    105, // our HSN, same as in sedutil, although looks like it can be arbitrary
+                    sp_uid,
+                    tiny_atom::UINT_01,
+                    challenge_tokens,
+                    if eprise {
+                        token_name!(b"SessionTimeout", 60000)
+                    } else {
+                        tokens![]
+                    }
+                ])
+                .build()
+        };
 
-        let response = unsafe { s.send_raw_command(command) }?;
+        let response = match unsafe { s.send_raw_command(command()) } {
+            // a session left open from before, e.g. after a crash mid-unlock
+            Err(super::Error::Opal { source: OpalError::Status { code: StatusCode::SP_BUSY }, .. }) => {
+                tracing::warn!("StartSession failed because of a stale session, resetting the ComID stack");
+                s.device.stack_reset()?;
+                unsafe { s.send_raw_command(command()) }
+            }
+            res => res,
+        };
+        let response = match response {
+            // the TPer checks the host challenge as part of StartSession
+            Err(super::Error::Opal { source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED }, .. }) if challenge.is_some() => {
+                return Err(super::Error::Authentication { authority: sign_authority });
+            }
+            res => res?,
+        };
 
-        s.hsn = response.get_uint(4) as _;
-        s.tsn = response.get_uint(5) as _;
-
-        match &challenge {
-            Some(_challenge) if s.device.is_eprise() => {
-                unimplemented!();
-                // LOG(D1) << "Entering DtaSession::authenticate ";
-                // 	vector<uint8_t> hash;
-                // 	DtaCommand *cmd = new DtaCommand();
-                // 	if (NULL == cmd) {
-                // 		LOG(E) << "Unable to create session object ";
-                // 		return DTAERROR_OBJECT_CREATE_FAILED;
-                // 	}
-                // 	DtaResponse response;
-                // 	cmd->reset(OPAL_UID::OPAL_THISSP_UID, OPAL_METHOD::EAUTHENTICATE);
-                // 	cmd->addToken(OPAL_TOKEN::STARTLIST); // [  (Open Bracket)
-                // 	cmd->addToken(Authority);
-                //     if (Challenge && *Challenge)
-                //     {
-                // 		cmd->addToken(OPAL_TOKEN::STARTNAME);
-                // 		if (d->isEprise())
-                // 			cmd->addToken("Challenge");
-                // 		else
-                // 			cmd->addToken(OPAL_TINY_ATOM::UINT_00);
-                // 		if (hashPwd) {
-                // 			hash.clear();
-                // 			DtaHashPwd(hash, Challenge, d);
-                // 			cmd->addToken(hash);
-                // 		}
-                // 		else
-                // 			cmd->addToken(Challenge);
-                // 		cmd->addToken(OPAL_TOKEN::ENDNAME);
-                //     }
-                // 	cmd->addToken(OPAL_TOKEN::ENDLIST); // ]  (Close Bracket)
-                // 	cmd->complete();
-                // 	if ((lastRC = sendCommand(cmd, response)) != 0) {
-                // 		LOG(E) << "Session Authenticate failed";
-                // 		delete cmd;
-                // 		return lastRC;
-                // 	}
-                // 	if (0 == response.getUint8(1)) {
-                // 		LOG(E) << "Session Authenticate failed (response = false)";
-                // 		delete cmd;
-                // 		return DTAERROR_AUTH_FAILED;
-                // 	}
+        // CALL SMUID SYNCSESSION [ HSN TSN ...
+        match (response.get_uint(4), response.get_uint(5)) {
+            (Some(hsn), Some(tsn)) => {
+                s.hsn = hsn as _;
+                s.tsn = tsn as _;
             }
+            _ => return Err(malformed("SyncSession is missing HSN / TSN")),
+        }
+
+        // Enterprise drives authenticate after the session has been started
+        match challenge {
+            Some(challenge) if s.device.is_eprise() => s.authenticate(sign_authority, challenge)?,
             _ => {}
         }
 
         Ok(s)
     }
 
+    /// Exchanges communication limits with the TPer's Session Manager and returns the negotiated ones.
+    pub fn exchange_properties(device: &'d mut SecureDevice<P>, host: Properties) -> crate::Result<Properties, P::Error> {
+        // not a session, there is nothing to close when this is dropped
+        let mut s = core::mem::ManuallyDrop::new(Self {
+            device,
+            tsn: 0,
+            hsn: 0,
+            protocol: 0x01,
+        });
+
+        let names = PROPERTY_NAMES.map(Value::from);
+        let host_values = [
+            host.max_com_packet_size,
+            host.max_packet_size,
+            host.max_ind_token_size,
+            host.max_packets,
+            host.max_subpackets,
+            host.max_methods,
+        ];
+        let host_properties = names.iter().zip(host_values)
+            .map(|(name, value)| Value::Name(Box::new(name.clone()), Box::new(Value::Uint(value as u64))))
+            .collect();
+        let command = OpalCommandBuilder::new(uid::OPAL_SMUID, method::PROPERTIES)
+            .payload(token_list![token_name!(token::HOSTPROPERTIES, Value::List(host_properties))])
+            .build();
+        let response = unsafe { s.send_raw_command(command) }?;
+
+        // CALL SMUID Properties [ [ TPer properties ] HostProperties = [ ... ] ]
+        let values = response.values().ok_or_else(|| malformed("Properties response is malformed"))?;
+        let tper = match values.get(3).and_then(Value::as_list) {
+            Some([tper @ Value::List(_), ..]) => tper,
+            _ => return Err(malformed("Properties response lacks the TPer properties")),
+        };
+        let get = |i: usize, default: u32| {
+            tper.get(&names[i]).and_then(Value::as_uint).map_or(default, |v| v.min(u32::MAX as u64) as u32)
+        };
+        let min = Properties::MINIMUM;
+        let tper = Properties {
+            max_com_packet_size: get(0, min.max_com_packet_size),
+            max_packet_size: get(1, min.max_packet_size),
+            max_ind_token_size: get(2, min.max_ind_token_size),
+            max_packets: get(3, min.max_packets),
+            max_subpackets: get(4, min.max_subpackets),
+            max_methods: get(5, min.max_methods),
+        };
+        tracing::debug!(?tper, "TPer properties");
+        Ok(tper.min(host))
+    }
+
     pub fn protocol(mut self, protocol: u8) -> Self {
         self.protocol = protocol;
         self
     }
 
-    pub unsafe fn send_raw_command(&mut self, mut command: OpalCommand) -> crate::Result<OpalResponse, P::Error> {
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn send_raw_command(&mut self, command: OpalCommand) -> crate::Result<OpalResponse, P::Error> {
+        let eod = command.eod;
+        let response = self.exchange(command)?;
+
+        let len = response.len();
+        if eod
+            && len >= 6
+            && response.is(len - 6, token::ENDOFDATA)
+            && response.is(len - 5, token::STARTLIST)
+            && response.is(len - 1, token::ENDLIST)
+        {
+            let code = match response.get_uint(len - 4) {
+                Some(code) => StatusCode(code as _),
+                None => return Err(malformed_com_packet("method status is not an unsigned integer")),
+            };
+            if code != StatusCode::SUCCESS {
+                Err(super::Error::Opal {
+                    source: OpalError::Status { code },
+                    msg: format!("NvmExpressPassthru received non-Success status code: {code:?}"),
+                })
+            } else {
+                Ok(response)
+            }
+        } else {
+            Err(super::Error::Opal {
+                source: OpalError::NoMethodStatus,
+                msg: "NvmExpressPassthru received non-conform data".to_owned(),
+            })
+        }
+    }
+
+    /// Sends the command within this session and receives the response, without looking at its tokens
+    unsafe fn exchange(&mut self, mut command: OpalCommand) -> crate::Result<OpalResponse, P::Error> {
         command.set_session(self.device.com_id(), self.tsn, self.hsn);
 
-        let eod = command.eod;
+        let max_payload = self.device.properties().max_payload();
+        if command.payload.len() > max_payload {
+            return Err(super::Error::Opal {
+                source: OpalError::CommandTooLarge,
+                msg: format!("command of {} bytes exceeds the negotiated maximum of {max_payload}", command.payload.len()),
+            });
+        }
 
         let mut header = command.header;
 
@@ -144,57 +226,112 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
             .secure_send(self.protocol, com_id, buffer.as_mut())
             .context(super::IoSnafu)?;
 
-        let mut buffer = crate::util::alloc_aligned(2048, self.device.proto().align());
+        let received = self.recv_response(com_id)?;
+        dump("received", &received);
 
-        let mut header: OpalHeader;
-        loop {
-            //sleep(Duration::from_millis(25));
+        OpalResponse::parse(&received).map_err(|source| super::Error::Opal {
+            source,
+            msg: "received a ComPacket with invalid lengths or tokens".to_owned(),
+        })
+    }
 
+    /// Receives a whole response into a buffer of the negotiated MaxComPacketSize. If the TPer reports that the response doesn't fit, the buffer is
+    /// enlarged to its MinTransfer. Responses split over several ComPackets (OutstandingData != 0
+    /// after a non-empty ComPacket) are joined into a single ComPacket. A response that isn't ready is
+    /// polled at most [`MAX_POLLS`] times.
+    unsafe fn recv_response(&mut self, com_id: u16) -> crate::Result<Vec<u8>, P::Error> {
+        let align = self.device.proto().align();
+        let len = (self.device.properties().max_com_packet_size as usize).clamp(512, MAX_RECV_BUFFER_LEN);
+        let mut buffer = crate::util::alloc_aligned(len, align);
+        // first ComPacket with the payloads of the following ones appended
+        let mut received: Option<Vec<u8>> = None;
+        let mut polls = 0;
+
+        loop {
             self.device
                 .proto()
                 .secure_recv(self.protocol, com_id, &mut buffer)
                 .context(super::IoSnafu)?;
 
-            header = core::ptr::read(buffer.as_ptr() as _);
+            let (header, payload) = com_packet_payload(&buffer)
+                .map_err(|_| malformed_com_packet("received a ComPacket with invalid lengths"))?;
+
+            if header.cp.length == 0 {
+                match (header.cp.outstanding_data, header.cp.min_transfer as usize) {
+                    // nothing (more) to receive
+                    (0, _) => break,
+                    // response not ready yet
+                    (_, 0) => {
+                        polls += 1;
+                        if polls == MAX_POLLS {
+                            return Err(super::Error::Opal { source: OpalError::NoResponse, msg: format!("no response on ComID {com_id:#x}") });
+                        }
+                        self.device.proto().stall(POLL_INTERVAL_US);
+                        continue;
+                    }
+                    (_, min_transfer) if min_transfer > MAX_RECV_BUFFER_LEN => {
+                        return Err(malformed_com_packet(&format!("TPer requests an IF-RECV of {min_transfer} bytes")));
+                    }
+                    (_, min_transfer) => {
+                        tracing::debug!("response needs a {min_transfer} byte buffer");
+                        // transfers are in whole blocks for most transports
+                        let len = min_transfer.next_multiple_of(512).max(buffer.len());
+                        buffer = crate::util::alloc_aligned(len, align);
+                        continue;
+                    }
+                }
+            }
 
-            // zeroes are zeroes in any endianess
-            if header.cp.outstanding_data == 0 || header.cp.min_transfer != 0 {
+            let joined = received.get_or_insert_with(|| buffer[..size_of::<OpalHeader>()].to_vec());
+            joined.extend(payload);
+            if joined.len() > MAX_RECV_BUFFER_LEN {
+                return Err(malformed_com_packet("multi-part response exceeds the maximum response size"));
+            }
+            if header.cp.outstanding_data == 0 {
                 break;
             }
         }
-        header.cp.length = u32::from_be(header.cp.length);
-        header.pkt.length = u32::from_be(header.pkt.length);
-        header.subpkt.length = u32::from_be(header.subpkt.length);
 
-        dump(
-            "received",
-            &buffer[..header.cp.length as usize + size_of_val(&header.cp)],
-        );
+        let Some(mut received) = received else {
+            // the last (empty) ComPacket
+            return Ok(buffer[..size_of::<OpalHeader>()].to_vec());
+        };
+        // fix up the lengths of the joined ComPacket
+        let subpkt_len = received.len() - size_of::<OpalHeader>();
+        let pkt_len = subpkt_len + size_of::<SubpacketHeader>();
+        let cp_len = pkt_len + size_of::<PacketHeader>();
+        received[16..20].copy_from_slice(&(cp_len as u32).to_be_bytes());
+        received[40..44].copy_from_slice(&(pkt_len as u32).to_be_bytes());
+        received[52..56].copy_from_slice(&(subpkt_len as u32).to_be_bytes());
+        Ok(received)
+    }
 
-        let response = OpalResponse::parse(header, buffer.as_ref());
+    /// Authenticates an additional authority within the session
+    /// (EAUTHENTICATE on Enterprise drives, AUTHENTICATE on Opal drives).
+    /// `Unsupported` if the TPer doesn't know the method, like some Opal 1.0 drives that can only
+    /// authenticate in StartSession.
+    pub fn authenticate(&mut self, authority: BS8, challenge: &[u8]) -> crate::Result<(), P::Error> {
+        let command = if self.device.is_eprise() {
+            OpalCommandBuilder::new(uid::OPAL_THISSP, method::EAUTHENTICATE)
+                .payload(token_list![authority, token_name!(b"Challenge", challenge)])
+                .build()
+        } else {
+            OpalCommandBuilder::new(uid::OPAL_THISSP, method::AUTHENTICATE)
+                .payload(token_list![authority, token_name!(tiny_atom::UINT_00, challenge)])
+                .build()
+        };
 
-        let len = response.len();
-        if eod
-            && len >= 6
-            && response.is(len - 6, token::ENDOFDATA)
-            && response.is(len - 5, token::STARTLIST)
-            && response.is(len - 1, token::ENDLIST)
-        {
-            let code = StatusCode(response.get_uint(len - 4) as _);
-            if code != StatusCode::SUCCESS {
-                Err(super::Error::Opal {
-                    source: OpalError::Status { code },
-                    msg: format!("NvmExpressPassthru received non-Success status code: {code:?}"),
-                })
-            } else {
-                Ok(response)
+        let response = match unsafe { self.send_raw_command(command) } {
+            Err(super::Error::Opal { source: OpalError::Status { code: StatusCode::INVALID_FUNCTION }, .. }) => {
+                return Err(super::Error::Unsupported);
             }
-        } else {
-            Err(super::Error::Opal {
-                source: OpalError::NoMethodStatus,
-                msg: "NvmExpressPassthru received non-conform data".to_owned(),
-            })
+            res => res?,
+        };
+        // [ success ]
+        if response.get_uint(1).ok_or_else(|| malformed("authentication result is not a boolean"))? == 0 {
+            return Err(super::Error::Authentication { authority });
         }
+        Ok(())
     }
 
     #[allow(clippy::missing_safety_doc)]
@@ -238,32 +375,340 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
             }
         }
 
-        let locking_range = if locking_range != 0 {
-            let mut bytes = uid::OPAL_LOCKINGRANGE_GLOBAL.bytes;
-            bytes[5] = 0x03;
-            bytes[7] = locking_range;
-            BS8::new(bytes, "LOCKING_RANGE_N")
+        let command = if self.device.is_eprise() {
+            // Enterprise has no archive states and uses column names instead of numbers
+            OpalCommandBuilder::new(uid::band(locking_range), method::ESET)
+                .payload(token_list![
+                    token_list![],
+                    token_list![token_list![
+                        token_name!(b"ReadLocked", read_lock),
+                        token_name!(b"WriteLocked", write_lock),
+                    ]],
+                ])
+                .build()
         } else {
-            uid::OPAL_LOCKINGRANGE_GLOBAL
+            OpalCommandBuilder::new(uid::locking_range(locking_range), method::SET)
+                .payload(token_list![token_name!(
+                    token::VALUES,
+                    token_list![
+                        token_name!(token::READLOCKED, read_lock),
+                        if archive_user {
+                            tokens![]
+                        } else {
+                            token_name!(token::WRITELOCKED, write_lock)
+                        }
+                    ]
+                )])
+                .build()
         };
 
-        let command = OpalCommandBuilder::new(locking_range, method::SET)
-            .payload(token_list![token_name!(
-                token::VALUES,
-                token_list![
-                    token_name!(token::READLOCKED, read_lock),
-                    if archive_user {
-                        tokens![]
-                    } else {
-                        token_name!(token::WRITELOCKED, write_lock)
-                    }
-                ]
-            )])
+        unsafe { self.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Sets a locking range to [`LockingState::ReadOnly`], enabling WriteLockEnabled in the same Set as WriteLocked
+    /// has no effect without it. Users may only set ReadLocked / WriteLocked (and can't Get the row to check it),
+    /// so only Admins and BandMasters can make a range read-only.
+    pub fn set_locking_range_read_only(&mut self, locking_range: u8) -> crate::Result<(), P::Error> {
+        let object = self.locking_range_uid(locking_range);
+        let values = [
+            (locking::WRITE_LOCK_ENABLED, true.into()),
+            (locking::READ_LOCKED, false.into()),
+            (locking::WRITE_LOCKED, true.into()),
+        ];
+        match self.set(object, &values) {
+            Err(super::Error::Opal { source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED }, .. }) => {
+                Err(super::Error::WriteLockDisabled { locking_range })
+            }
+            res => res,
+        }
+    }
+
+    /// Reads ReadLocked and WriteLocked of a locking range (a band on Enterprise drives).
+    pub fn locking_range_state(&mut self, locking_range: u8) -> crate::Result<LockingState, P::Error> {
+        let object = self.locking_range_uid(locking_range);
+        let row = self.get(object, locking::READ_LOCKED, locking::WRITE_LOCKED)?;
+
+        match (row.bool(locking::READ_LOCKED), row.bool(locking::WRITE_LOCKED)) {
+            (Some(false), Some(false)) => Ok(LockingState::ReadWrite),
+            (Some(false), Some(true)) => Ok(LockingState::ReadOnly),
+            (Some(true), Some(_)) => Ok(LockingState::Locked),
+            _ => Err(malformed("Get of ReadLocked / WriteLocked returned incomplete data")),
+        }
+    }
+
+    /// Sets the PIN column of a C_PIN row, e.g. [`Authority::c_pin`]; `pin` is used as is.
+    pub fn set_pin(&mut self, c_pin: BS8, pin: &[u8]) -> crate::Result<(), P::Error> {
+        self.set(c_pin, &[(c_pin::PIN, Value::from(pin))])
+    }
+
+    /// UID of the media encryption key (K_AES_128 / K_AES_256 row) of a locking range
+    pub fn active_key(&mut self, locking_range: u8) -> crate::Result<BS8, P::Error> {
+        let row = self.get(uid::locking_range(locking_range), locking::ACTIVE_KEY, locking::ACTIVE_KEY)?;
+        match row.get(locking::ACTIVE_KEY).and_then(Value::as_bytes).map(<[u8; 8]>::try_from) {
+            Some(Ok(key)) => Ok(BS8::new(key, "ACTIVE_KEY")),
+            _ => Err(malformed("Get of ActiveKey returned no UID")),
+        }
+    }
+
+    /// Cryptographically erases a locking range by replacing its media encryption key: GenKey on the
+    /// ActiveKey on Opal drives, Erase on the band on Enterprise drives (which also resets the band's
+    /// locking columns and the password of its BandMaster to MSID).
+    pub fn erase_locking_range(&mut self, locking_range: u8) -> crate::Result<(), P::Error> {
+        let command = match self.device.is_eprise() {
+            true => OpalCommandBuilder::new(uid::band(locking_range), method::ERASE),
+            false => OpalCommandBuilder::new(self.active_key(locking_range)?, method::GENKEY),
+        };
+        unsafe { self.send_raw_command(command.payload(token_list![]).build()) }?;
+        Ok(())
+    }
+
+    /// Activates an SP in Manufactured-Inactive state, i.e. the Locking SP, from an Admin SP session as SID.
+    pub fn activate(&mut self, sp: BS8) -> crate::Result<(), P::Error> {
+        let command = OpalCommandBuilder::new(sp, method::ACTIVATE)
+            .payload(token_list![])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Activates `sp` like [`Self::activate`], putting the locking ranges `ranges` into Single User Mode.
+    /// `admins_only` is the RangeStartRangeLengthPolicy: 1 lets only the admins set RangeStart / RangeLength,
+    /// 0 the users of the ranges as well.
+    pub fn activate_single_user(&mut self, sp: BS8, ranges: &[u8], admins_only: bool) -> crate::Result<(), P::Error> {
+        let selection = ranges.iter().map(|&n| Value::Bytes(uid::locking_range(n).bytes.to_vec())).collect();
+        let command = OpalCommandBuilder::new(sp, method::ACTIVATE)
+            .payload(token_list![
+                token_name!(SINGLE_USER_SELECTION_LIST, Value::List(selection)),
+                token_name!(RANGE_START_RANGE_LENGTH_POLICY, admins_only as u64),
+            ])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Reverts `sp` to its factory state from an Admin SP session; reverting the Admin SP reverts the whole TPer.
+    pub fn revert(self, sp: BS8) -> crate::Result<(), P::Error> {
+        let command = OpalCommandBuilder::new(sp, method::REVERT)
+            .payload(token_list![])
+            .build();
+        self.send_ending_session(command)
+    }
+
+    /// Reverts the SP of this session to its factory state (RevertSP).
+    pub fn revert_sp(self) -> crate::Result<(), P::Error> {
+        let command = OpalCommandBuilder::new(uid::OPAL_THISSP, method::REVERTSP)
+            .payload(token_list![])
             .build();
+        self.send_ending_session(command)
+    }
 
+    /// Sends a method after which the TPer aborts the session if it succeeded,
+    /// so there's no session left to close.
+    fn send_ending_session(mut self, command: OpalCommand) -> crate::Result<(), P::Error> {
         unsafe { self.send_raw_command(command) }?;
+        core::mem::forget(self);
+        Ok(())
+    }
+
+    /// `count` random bytes from the TPer's random number generator, in as many calls as needed
+    /// as TPers only have to return up to 32 bytes at a time.
+    pub fn random(&mut self, count: usize) -> crate::Result<Vec<u8>, P::Error> {
+        let mut random = Vec::with_capacity(count);
+        while random.len() < count {
+            let chunk = (count - random.len()).min(32);
+            let command = OpalCommandBuilder::new(uid::OPAL_THISSP, method::RANDOM)
+                .payload(token_list![chunk as u64])
+                .build();
+            let response = unsafe { self.send_raw_command(command) }?;
+            match response.results().as_deref() {
+                Some([Value::Bytes(bytes)]) if bytes.len() == chunk => random.extend(bytes),
+                _ => return Err(malformed("Random returned no or too few bytes")),
+            }
+        }
+        Ok(random)
+    }
+
+    /// Starts a transaction: the effects of the following method calls only persist once it's committed,
+    /// aborting it or closing the session undoes them. The Opal SSC doesn't require TPers to support
+    /// transactions, those that don't refuse to start one.
+    pub fn begin_transaction(&mut self) -> crate::Result<(), P::Error> {
+        match self.transaction_control(token::STARTTRANSACTON, 0)? {
+            0 => Ok(()),
+            status => Err(transaction_failure(format!("TPer refused to start a transaction: status {status:#x}"))),
+        }
+    }
+
+    /// Commits the open transaction, fails with TRANSACTION_FAILURE if the TPer aborted it instead
+    pub fn commit_transaction(&mut self) -> crate::Result<(), P::Error> {
+        match self.transaction_control(token::ENDTRANSACTON, 0)? {
+            0 => Ok(()),
+            status => Err(transaction_failure(format!("TPer aborted the transaction: status {status:#x}"))),
+        }
+    }
+
+    /// Aborts the open transaction, undoing everything done since it was started
+    pub fn abort_transaction(&mut self) -> crate::Result<(), P::Error> {
+        // any status means aborted
+        self.transaction_control(token::ENDTRANSACTON, 1).map(drop)
+    }
+
+    /// Runs `f` in a transaction which is committed if it succeeds and aborted if it fails
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> crate::Result<T, P::Error>) -> crate::Result<T, P::Error> {
+        self.begin_transaction()?;
+        match f(self) {
+            Ok(value) => {
+                self.commit_transaction()?;
+                Ok(value)
+            }
+            Err(e) => {
+                if let Err(abort) = self.abort_transaction() {
+                    tracing::error!("failed to abort transaction: {:?}", abort);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends a StartTransaction / EndTransaction token with its status and returns the TPer's status
+    fn transaction_control(&mut self, control: SimpleToken, status: u8) -> crate::Result<u8, P::Error> {
+        let command = OpalCommandBuilder::empty()
+            .payload(tokens![control, status as u64])
+            .build_no_end_of_data();
+        let response = unsafe { self.exchange(command) }?;
+        match response.get_uint(1) {
+            Some(status) if response.len() == 2 && response.is(0, control) => Ok(status as u8),
+            _ => Err(malformed_com_packet("TPer didn't answer the transaction control token")),
+        }
+    }
+
+    /// Reads `len` bytes of the DataStore table starting at `offset`, in as many Gets as the negotiated
+    /// properties require.
+    pub fn read_datastore(&mut self, offset: u32, len: usize) -> crate::Result<Vec<u8>, P::Error> {
+        let chunk_len = self.device.properties().max_get_bytes().max(1);
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let start = offset as u64 + data.len() as u64;
+            let end = start + chunk_len.min(len - data.len()) as u64 - 1;
+            let command = OpalCommandBuilder::new(uid::OPAL_DATASTORE, method::GET)
+                .payload(token_list![token_list![
+                    token_name!(token::STARTROW, start),
+                    token_name!(token::ENDROW, end),
+                ]])
+                .build();
+            let response = unsafe { self.send_raw_command(command) }?;
+            // [ bytes ]
+            match response.results().as_deref() {
+                Some([Value::Bytes(bytes)]) if bytes.len() as u64 == end - start + 1 => data.extend(bytes),
+                _ => return Err(malformed("Get of the DataStore returned the wrong amount of bytes")),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` to the DataStore table starting at `offset`, in as many Sets as the negotiated
+    /// properties require.
+    pub fn write_datastore(&mut self, offset: u32, data: &[u8]) -> crate::Result<(), P::Error> {
+        let chunk_len = self.device.properties().max_set_bytes().max(1);
+        for (i, chunk) in data.chunks(chunk_len).enumerate() {
+            let start = offset as u64 + (i * chunk_len) as u64;
+            let command = OpalCommandBuilder::new(uid::OPAL_DATASTORE, method::SET)
+                .payload(token_list![
+                    token_name!(token::WHERE, start),
+                    token_name!(token::VALUES, chunk),
+                ])
+                .build();
+            unsafe { self.send_raw_command(command) }?;
+        }
         Ok(())
     }
+
+    /// Reads a whole row of the Locking table (Band on Enterprise drives).
+    pub fn locking_range(&mut self, locking_range: u8) -> crate::Result<LockingRangeRow, P::Error> {
+        let object = self.locking_range_uid(locking_range);
+        let row = self.get(object, LockingRangeRow::FIRST_COLUMN, LockingRangeRow::LAST_COLUMN)?;
+        LockingRangeRow::from_row(&row)
+            .ok_or_else(|| malformed("Get of a locking range returned incomplete data"))
+    }
+
+    /// Writes a whole row of the Locking table, except for start and length of the global range.
+    pub fn set_locking_range_row(&mut self, locking_range: u8, row: &LockingRangeRow) -> crate::Result<(), P::Error> {
+        let object = self.locking_range_uid(locking_range);
+        self.set(object, &row.to_values(locking_range == 0))
+    }
+
+    fn locking_range_uid(&self, locking_range: u8) -> BS8 {
+        match self.device.is_eprise() {
+            true => uid::band(locking_range),
+            false => uid::locking_range(locking_range),
+        }
+    }
+
+    /// Get (EGet on Enterprise drives) of the columns `start..=end` of a row
+    pub fn get(&mut self, object: BS8, start: Column, end: Column) -> crate::Result<Row, P::Error> {
+        let eprise = self.device.is_eprise();
+        let (method, start_column, end_column) = match eprise {
+            true => (method::EGET, Value::from("startColumn"), Value::from("endColumn")),
+            false => (method::GET, Value::Uint(token::STARTCOLUMN.token as u64), Value::Uint(token::ENDCOLUMN.token as u64)),
+        };
+        let command = OpalCommandBuilder::new(object, method)
+            .payload(token_list![Value::List(vec![
+                Value::Name(Box::new(start_column), Box::new(start.key(eprise))),
+                Value::Name(Box::new(end_column), Box::new(end.key(eprise))),
+            ])])
+            .build();
+        let response = unsafe { self.send_raw_command(command) }?;
+        response.results()
+            .and_then(|results| Row::from_results(&results))
+            .ok_or_else(|| malformed("Get returned a malformed row"))
+    }
+
+    /// Set (ESet on Enterprise drives) of columns of a row
+    pub fn set(&mut self, object: BS8, values: &[(Column, Value)]) -> crate::Result<(), P::Error> {
+        let eprise = self.device.is_eprise();
+        let values = Value::List(values.iter()
+            .map(|(column, value)| Value::Name(Box::new(column.key(eprise)), Box::new(value.clone())))
+            .collect());
+        let command = match eprise {
+            // [ where = [], values = [ row ] ]
+            true => OpalCommandBuilder::new(object, method::ESET)
+                .payload(token_list![token_list![], Value::List(vec![values])]),
+            false => OpalCommandBuilder::new(object, method::SET)
+                .payload(token_list![token_name!(token::VALUES, values)]),
+        };
+        unsafe { self.send_raw_command(command.build()) }?;
+        Ok(())
+    }
+}
+
+const PROPERTY_NAMES: [&str; 6] = [
+    "MaxComPacketSize",
+    "MaxPacketSize",
+    "MaxIndTokenSize",
+    "MaxPackets",
+    "MaxSubpackets",
+    "MaxMethods",
+];
+
+fn malformed_com_packet<E: core::fmt::Debug + core::fmt::Display + snafu::AsErrorSource>(msg: &str) -> super::Error<E> {
+    super::Error::Opal {
+        source: OpalError::MalformedComPacket,
+        msg: msg.to_owned(),
+    }
+}
+
+fn transaction_failure<E: core::fmt::Debug + core::fmt::Display + snafu::AsErrorSource>(msg: String) -> super::Error<E> {
+    super::Error::Opal {
+        source: OpalError::Status { code: StatusCode::TRANSACTION_FAILURE },
+        msg,
+    }
+}
+
+fn malformed<E: core::fmt::Debug + core::fmt::Display + snafu::AsErrorSource>(msg: &str) -> super::Error<E> {
+    super::Error::Opal {
+        source: OpalError::MalformedResponse,
+        msg: msg.to_owned(),
+    }
 }
 
 impl<'d, P: SecureProtocol> Drop for OpalSession<'d, P> {
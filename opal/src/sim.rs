@@ -0,0 +1,1696 @@
+//! Software TPer speaking enough of TCG Opal 2.0 (and its Opal 1.0, Pyrite, Ruby and Enterprise
+//! relatives) to drive the whole crate from host-side tests.
+//!
+//! It implements [`SecureProtocol`] on top of an in-memory model of the Admin SP and the Locking SP:
+//! Level 0 discovery, ComID management, Block SID, StartSession with authentication, Get / Set on
+//! the Locking, MBRControl and C_PIN tables and the DataStore, Activate (optionally into Single
+//! User Mode) / Revert / RevertSP, GenKey / Erase, Random, transactions and try-limits with lockout.
+//! Everything is deliberately simple: only a single session may be open at a time and access
+//! control is hardcoded instead of read from ACE tables.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use snafu::Snafu;
+
+use crate::defs::{method, token, uid, StatusCode, BS8};
+use crate::io::{FeatureCodes, LockingFlags, SecureProtocol, Ssc};
+
+#[cfg(test)]
+mod tests;
+
+pub const BASE_COM_ID: u16 = 0x1000;
+pub const ENTERPRISE_COM_ID: u16 = 0x07FE;
+const NUM_LOCKING_ADMINS: u8 = 4;
+/// one more than the locking ranges, Single User Mode needs a user for every one of them
+const NUM_LOCKING_USERS: u8 = NUM_LOCKING_RANGES + 1;
+const NUM_LOCKING_RANGES: u8 = 8;
+const DEFAULT_TRY_LIMIT: u32 = 5;
+const DATASTORE_SIZE: usize = 0x0010_0000;
+const DEFAULT_MSID: &[u8] = b"MSID-SIMULATED-TPER";
+/// printed on the label, 32 characters like on real drives
+pub const PSID: &[u8] = b"PSIDSIMULATEDTPER000000000000000";
+
+const COM_PACKET_HEADER_LEN: usize = 20;
+const PACKET_HEADER_LEN: usize = 24;
+const SUBPACKET_HEADER_LEN: usize = 12;
+const HEADER_LEN: usize = COM_PACKET_HEADER_LEN + PACKET_HEADER_LEN + SUBPACKET_HEADER_LEN;
+
+#[derive(Debug, Snafu)]
+pub enum SimError {
+    UnsupportedProtocol { protocol: u8 },
+    UnknownComId { com_id: u16 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Sp {
+    Admin,
+    Locking,
+}
+
+#[derive(Debug, Clone)]
+struct CPin {
+    pin: Vec<u8>,
+    tries: u32,
+    try_limit: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Authority {
+    sp: Sp,
+    c_pin: u64,
+    enabled: bool,
+    admin: bool,
+}
+
+/// State of a single row of the Locking table; index 0 is the global range.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LockingRange {
+    pub start: u64,
+    pub length: u64,
+    pub read_lock_enabled: bool,
+    pub write_lock_enabled: bool,
+    pub read_locked: bool,
+    pub write_locked: bool,
+    pub lock_on_reset: bool,
+    /// incremented whenever the media encryption key is regenerated
+    pub key_generation: u32,
+    /// users (by number) that may set ReadLocked / WriteLocked of this range
+    users: Vec<u8>,
+    /// owned exclusively by its user, admins may neither lock nor unlock it
+    pub single_user: bool,
+}
+
+impl LockingRange {
+    pub fn is_locked(&self) -> bool {
+        (self.read_lock_enabled && self.read_locked) || (self.write_lock_enabled && self.write_locked)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MbrControl {
+    pub enable: bool,
+    pub done: bool,
+    pub done_on_reset: bool,
+}
+
+#[derive(Debug)]
+struct Session {
+    tsn: u32,
+    hsn: u32,
+    sp: Sp,
+    write: bool,
+    /// Anybody, the authority of StartSession and any further authenticated ones
+    authorities: Vec<u64>,
+    /// state to go back to if the open transaction is aborted
+    transaction: Option<Box<Snapshot>>,
+}
+
+/// Everything a transaction may change
+#[derive(Debug)]
+struct Snapshot {
+    locking_sp_active: bool,
+    c_pins: BTreeMap<u64, CPin>,
+    authorities: BTreeMap<u64, Authority>,
+    ranges: Vec<LockingRange>,
+    mbr: MbrControl,
+    datastore: Vec<u8>,
+}
+
+pub struct SimulatedTper {
+    /// Security Subsystem Class the simulated drive implements
+    ssc: Ssc,
+    serial: Vec<u8>,
+    align: usize,
+    msid: Vec<u8>,
+    locking_sp_active: bool,
+    c_pins: BTreeMap<u64, CPin>,
+    authorities: BTreeMap<u64, Authority>,
+    ranges: Vec<LockingRange>,
+    mbr: MbrControl,
+    datastore: Vec<u8>,
+    session: Option<Session>,
+    next_tsn: u32,
+    /// ComPackets of the pending response
+    responses: VecDeque<Vec<u8>>,
+    /// split responses into ComPackets with at most that many payload bytes
+    split: Option<usize>,
+    /// IF-RECVs answered with "not ready yet" before each ComPacket is sent
+    response_delay: usize,
+    /// IF-RECVs answered that way for the next ComPacket so far
+    delayed_polls: usize,
+    /// calls of `stall`
+    stalls: usize,
+    max_com_packet_size: u32,
+    /// as sent by the host with the Properties method
+    host_properties: Vec<Value>,
+    reconnects: usize,
+    /// method calls within sessions
+    method_calls: usize,
+    corrupt: Option<fn(&mut Vec<u8>)>,
+    /// Anybody may read TryLimit / Tries of the C_PIN rows
+    public_tries: bool,
+    /// xorshift state of the Random method, predictable on purpose
+    random: u64,
+    /// the Block SID feature is reported in Level 0 discovery
+    block_sid_supported: bool,
+    /// SID authentication is blocked until the next power cycle
+    sid_blocked: bool,
+    /// ... or hardware reset
+    block_sid_hardware_reset: bool,
+    /// response to the last ComID management request
+    com_id_response: Option<Vec<u8>>,
+    stack_resets: usize,
+    /// the Single User Mode feature is reported in Level 0 discovery and Activate accepts its parameters
+    single_user_supported: bool,
+    /// RangeStartRangeLengthPolicy: only the admins set RangeStart / RangeLength of Single User Mode ranges,
+    /// not their users as well
+    single_user_policy: bool,
+}
+
+fn uid(bs8: BS8) -> u64 {
+    u64::from_be_bytes(bs8.bytes)
+}
+
+const ANYBODY: u64 = 0x0000_0009_0000_0001;
+const SID: u64 = 0x0000_0009_0000_0006;
+const PSID_AUTHORITY: u64 = 0x0000_0009_0001_FF01;
+const ADMIN_SP_ADMIN1: u64 = 0x0000_0009_0000_0201;
+const LOCKING_ADMIN_BASE: u64 = 0x0000_0009_0001_0000;
+const LOCKING_USER_BASE: u64 = 0x0000_0009_0003_0000;
+const C_PIN_SID: u64 = 0x0000_000B_0000_0001;
+const C_PIN_MSID: u64 = 0x0000_000B_0000_8402;
+const C_PIN_PSID: u64 = 0x0000_000B_0001_FF01;
+const C_PIN_ADMIN_SP_ADMIN1: u64 = 0x0000_000B_0000_0201;
+const C_PIN_LOCKING_ADMIN_BASE: u64 = 0x0000_000B_0001_0000;
+const C_PIN_LOCKING_USER_BASE: u64 = 0x0000_000B_0003_0000;
+const LOCKING_GLOBAL_RANGE: u64 = 0x0000_0802_0000_0001;
+const LOCKING_RANGE_BASE: u64 = 0x0000_0802_0003_0000;
+const MBR_CONTROL: u64 = 0x0000_0803_0000_0001;
+const DATASTORE: u64 = 0x0000_1001_0000_0000;
+const K_AES_256_GLOBAL_RANGE: u64 = 0x0000_0806_0000_0001;
+const K_AES_256_RANGE_BASE: u64 = 0x0000_0806_0003_0000;
+const BAND_MASTER_BASE: u64 = 0x0000_0009_0000_8001;
+const ERASE_MASTER: u64 = 0x0000_0009_0000_8401;
+const C_PIN_BAND_MASTER_BASE: u64 = 0x0000_000B_0000_8001;
+const C_PIN_ERASE_MASTER: u64 = 0x0000_000B_0000_8401;
+
+/// Enterprise addresses Locking table columns by name
+const ENTERPRISE_COLUMNS: &[(&[u8], u64)] = &[
+    (b"RangeStart", 3),
+    (b"RangeLength", 4),
+    (b"ReadLockEnabled", 5),
+    (b"WriteLockEnabled", 6),
+    (b"ReadLocked", 7),
+    (b"WriteLocked", 8),
+    (b"LockOnReset", 9),
+];
+
+impl SimulatedTper {
+    /// A drive in factory state: SID is set to MSID and the Locking SP is inactive.
+    pub fn new(serial: &[u8]) -> Self {
+        let msid = DEFAULT_MSID.to_vec();
+        let mut c_pins = BTreeMap::new();
+        let mut authorities = BTreeMap::new();
+
+        let mut add = |auth: u64, c_pin: u64, sp: Sp, enabled: bool, admin: bool, pin: &[u8]| {
+            authorities.insert(auth, Authority { sp, c_pin, enabled, admin });
+            c_pins.insert(c_pin, CPin { pin: pin.to_vec(), tries: 0, try_limit: DEFAULT_TRY_LIMIT });
+        };
+        add(SID, C_PIN_SID, Sp::Admin, true, true, &msid);
+        add(ADMIN_SP_ADMIN1, C_PIN_ADMIN_SP_ADMIN1, Sp::Admin, false, true, b"");
+        // PSID may only Revert
+        add(PSID_AUTHORITY, C_PIN_PSID, Sp::Admin, true, false, PSID);
+        for n in 1..=NUM_LOCKING_ADMINS as u64 {
+            add(LOCKING_ADMIN_BASE + n, C_PIN_LOCKING_ADMIN_BASE + n, Sp::Locking, n == 1, true, b"");
+        }
+        for n in 1..=NUM_LOCKING_USERS as u64 {
+            add(LOCKING_USER_BASE + n, C_PIN_LOCKING_USER_BASE + n, Sp::Locking, false, false, b"");
+        }
+        c_pins.insert(C_PIN_MSID, CPin { pin: msid.clone(), tries: 0, try_limit: 0 });
+
+        Self {
+            ssc: Ssc::OpalV2,
+            serial: serial.to_vec(),
+            align: 4,
+            msid,
+            locking_sp_active: false,
+            c_pins,
+            authorities,
+            ranges: vec![LockingRange::default(); NUM_LOCKING_RANGES as usize + 1],
+            mbr: MbrControl { done_on_reset: true, ..MbrControl::default() },
+            datastore: vec![0; DATASTORE_SIZE],
+            session: None,
+            next_tsn: 0x1000,
+            responses: VecDeque::new(),
+            split: None,
+            response_delay: 0,
+            delayed_polls: 0,
+            stalls: 0,
+            max_com_packet_size: 0x4000,
+            host_properties: Vec::new(),
+            reconnects: 0,
+            method_calls: 0,
+            corrupt: None,
+            public_tries: false,
+            block_sid_supported: false,
+            sid_blocked: false,
+            block_sid_hardware_reset: false,
+            com_id_response: None,
+            stack_resets: 0,
+            single_user_supported: false,
+            single_user_policy: false,
+            random: serial.iter().fold(0x9E37_79B9_7F4A_7C15, |acc, &b| (acc ^ b as u64).rotate_left(8)),
+        }
+    }
+
+    /// A drive set up the way `sedutil-cli --initialsetup` and `--enablelockingrange 0` leave it:
+    /// SID and Admin1 share `pin`, the global range is locked and the MBR shadow is enabled.
+    pub fn provisioned(serial: &[u8], pin: &[u8]) -> Self {
+        let mut tper = Self::new(serial);
+        tper.locking_sp_active = true;
+        tper.c_pins.get_mut(&C_PIN_SID).unwrap().pin = pin.to_vec();
+        tper.set_admin_pin(1, pin);
+        tper.ranges[0] = LockingRange {
+            read_lock_enabled: true,
+            write_lock_enabled: true,
+            read_locked: true,
+            write_locked: true,
+            lock_on_reset: true,
+            ..LockingRange::default()
+        };
+        tper.mbr.enable = true;
+        tper
+    }
+
+    /// Like [`SimulatedTper::provisioned`], but an Opal 1.0 drive.
+    pub fn opal_v1(serial: &[u8], pin: &[u8]) -> Self {
+        let mut tper = Self::provisioned(serial, pin);
+        tper.ssc = Ssc::OpalV1;
+        tper
+    }
+
+    /// Like [`SimulatedTper::provisioned`], but a Pyrite 2.0 drive: no media encryption and only
+    /// the global range.
+    pub fn pyrite(serial: &[u8], pin: &[u8]) -> Self {
+        let mut tper = Self::provisioned(serial, pin);
+        tper.ssc = Ssc::PyriteV2;
+        tper.ranges.truncate(1);
+        tper
+    }
+
+    /// Like [`SimulatedTper::provisioned`], but a Ruby drive without MBR shadowing.
+    pub fn ruby(serial: &[u8], pin: &[u8]) -> Self {
+        let mut tper = Self::provisioned(serial, pin);
+        tper.ssc = Ssc::Ruby;
+        tper.mbr.enable = false;
+        tper
+    }
+
+    /// A provisioned Enterprise drive: BandMaster0 and EraseMaster use `pin`, all other BandMasters
+    /// still use MSID, and band 0 is locked.
+    pub fn enterprise(serial: &[u8], pin: &[u8]) -> Self {
+        let mut tper = Self::new(serial);
+        tper.ssc = Ssc::Enterprise;
+        // the Enterprise Locking SP is always active and has no Admin / User authorities
+        tper.locking_sp_active = true;
+        tper.authorities.retain(|_, a| a.sp == Sp::Admin);
+        tper.c_pins.retain(|&c_pin, _| [C_PIN_SID, C_PIN_MSID, C_PIN_PSID, C_PIN_ADMIN_SP_ADMIN1].contains(&c_pin));
+        for n in 0..=NUM_LOCKING_RANGES as u64 {
+            tper.add_authority(BAND_MASTER_BASE + n, C_PIN_BAND_MASTER_BASE + n, Sp::Locking, false);
+        }
+        tper.add_authority(ERASE_MASTER, C_PIN_ERASE_MASTER, Sp::Locking, false);
+        tper.set_band_master_pin(0, pin);
+        tper.c_pins.get_mut(&C_PIN_ERASE_MASTER).unwrap().pin = pin.to_vec();
+        tper.ranges[0] = LockingRange {
+            read_lock_enabled: true,
+            write_lock_enabled: true,
+            read_locked: true,
+            write_locked: true,
+            lock_on_reset: true,
+            ..LockingRange::default()
+        };
+        tper
+    }
+
+    fn add_authority(&mut self, authority: u64, c_pin: u64, sp: Sp, admin: bool) {
+        self.authorities.insert(authority, Authority { sp, c_pin, enabled: true, admin });
+        let pin = self.msid.clone();
+        self.c_pins.insert(c_pin, CPin { pin, tries: 0, try_limit: DEFAULT_TRY_LIMIT });
+    }
+
+    fn com_id(&self) -> u16 {
+        match self.ssc {
+            Ssc::Enterprise => ENTERPRISE_COM_ID,
+            _ => BASE_COM_ID,
+        }
+    }
+
+    pub fn set_band_master_pin(&mut self, n: u8, pin: &[u8]) {
+        self.c_pins.get_mut(&(C_PIN_BAND_MASTER_BASE + n as u64)).unwrap().pin = pin.to_vec();
+    }
+
+    pub fn msid(&self) -> &[u8] {
+        &self.msid
+    }
+
+    pub fn set_admin_pin(&mut self, n: u8, pin: &[u8]) {
+        self.c_pins.get_mut(&(C_PIN_LOCKING_ADMIN_BASE + n as u64)).unwrap().pin = pin.to_vec();
+    }
+
+    pub fn set_user_pin(&mut self, n: u8, pin: &[u8]) {
+        self.c_pins.get_mut(&(C_PIN_LOCKING_USER_BASE + n as u64)).unwrap().pin = pin.to_vec();
+    }
+
+    pub fn enable_user(&mut self, n: u8) {
+        self.authorities.get_mut(&(LOCKING_USER_BASE + n as u64)).unwrap().enabled = true;
+    }
+
+    /// Allow user `n` to set ReadLocked / WriteLocked of `range`.
+    pub fn grant_range(&mut self, range: u8, user: u8) {
+        self.ranges[range as usize].users.push(user);
+    }
+
+    /// Sets the TryLimit of every C_PIN row except MSID; 0 means unlimited.
+    pub fn set_try_limit(&mut self, try_limit: u32) {
+        for (&c_pin, row) in &mut self.c_pins {
+            if c_pin != C_PIN_MSID {
+                row.try_limit = try_limit;
+            }
+        }
+    }
+
+    /// Let Anybody read TryLimit / Tries like some TPers do, the Opal SSC only requires admins to.
+    pub fn set_public_tries(&mut self, public_tries: bool) {
+        self.public_tries = public_tries;
+    }
+
+    /// Report the Block SID feature and accept its command.
+    pub fn support_block_sid(&mut self) {
+        self.block_sid_supported = true;
+    }
+
+    /// Report the Single User Mode feature and accept its parameters to Activate.
+    pub fn support_single_user_mode(&mut self) {
+        self.single_user_supported = true;
+    }
+
+    pub fn sid_blocked(&self) -> bool {
+        self.sid_blocked
+    }
+
+    pub fn admin_tries(&self, n: u8) -> u32 {
+        self.c_pins[&(C_PIN_LOCKING_ADMIN_BASE + n as u64)].tries
+    }
+
+    pub fn range(&self, n: u8) -> &LockingRange {
+        &self.ranges[n as usize]
+    }
+
+    pub fn range_mut(&mut self, n: u8) -> &mut LockingRange {
+        &mut self.ranges[n as usize]
+    }
+
+    pub fn mbr(&self) -> &MbrControl {
+        &self.mbr
+    }
+
+    pub fn mbr_mut(&mut self) -> &mut MbrControl {
+        &mut self.mbr
+    }
+
+    pub fn datastore(&self) -> &[u8] {
+        &self.datastore
+    }
+
+    pub fn datastore_mut(&mut self) -> &mut [u8] {
+        &mut self.datastore
+    }
+
+    pub fn locking_sp_active(&self) -> bool {
+        self.locking_sp_active
+    }
+
+    pub fn has_open_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// how often the host reset the ComID stack with STACK_RESET
+    pub fn stack_resets(&self) -> usize {
+        self.stack_resets
+    }
+
+    /// how often `reconnect_controller` was called
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    pub fn method_calls(&self) -> usize {
+        self.method_calls
+    }
+
+    /// Replaces the MSID, including the PINs still set to it.
+    pub fn set_msid(&mut self, msid: &[u8]) {
+        for row in self.c_pins.values_mut() {
+            if row.pin == self.msid {
+                row.pin = msid.to_vec();
+            }
+        }
+        self.msid = msid.to_vec();
+    }
+
+    /// MaxComPacketSize reported by Properties; larger ComPackets of the host are ignored and
+    /// responses larger than this or the host's limit are replaced by RESPONSE_OVERFLOW.
+    pub fn set_max_com_packet_size(&mut self, max_com_packet_size: u32) {
+        self.max_com_packet_size = max_com_packet_size;
+    }
+
+    /// A property the host sent with the Properties method
+    pub fn host_property(&self, name: &str) -> Option<u64> {
+        self.host_properties.iter().find_map(|value| match value {
+            Value::Name(n, v) => match (&**n, &**v) {
+                (Value::Bytes(n), Value::Uint(v)) if n == name.as_bytes() => Some(*v),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Sends responses as several ComPackets with at most `max_payload` bytes each,
+    /// OutstandingData announcing the rest.
+    pub fn split_responses(&mut self, max_payload: usize) {
+        self.split = Some(max_payload);
+    }
+
+    /// Answers `polls` IF-RECVs with "not ready yet" (OutstandingData without MinTransfer)
+    /// before each ComPacket of a response.
+    pub fn delay_responses(&mut self, polls: usize) {
+        self.response_delay = polls;
+    }
+
+    /// how often the host waited with `stall`
+    pub fn stalls(&self) -> usize {
+        self.stalls
+    }
+
+    /// Passes every following session response through `corrupt` before it's sent to the host,
+    /// to simulate a buggy or malicious drive.
+    pub fn corrupt_responses(&mut self, corrupt: fn(&mut Vec<u8>)) {
+        self.corrupt = Some(corrupt);
+    }
+
+    /// Simulates a power cycle: sessions are aborted, try counters reset and LockOnReset applied.
+    pub fn power_cycle(&mut self) {
+        self.end_session();
+        self.responses.clear();
+        self.sid_blocked = false;
+        for row in self.c_pins.values_mut() {
+            row.tries = 0;
+        }
+        for range in &mut self.ranges {
+            if range.lock_on_reset {
+                range.read_locked = range.read_lock_enabled;
+                range.write_locked = range.write_lock_enabled;
+            }
+        }
+        if self.mbr.done_on_reset {
+            self.mbr.done = false;
+        }
+    }
+
+    fn locking_flags(&self) -> LockingFlags {
+        let mut flags = LockingFlags::LOCKING_SUPPORTED;
+        if self.media_encryption() {
+            flags |= LockingFlags::MEDIA_ENCRYPTION;
+        }
+        if !self.mbr_shadowing() {
+            flags |= LockingFlags::MBR_SHADOWING_NOT_SUPPORTED;
+        }
+        let ranges = self.ranges.iter();
+        if self.locking_sp_active && ranges.clone().any(|r| r.read_lock_enabled || r.write_lock_enabled) {
+            flags |= LockingFlags::LOCKING_ENABLED;
+        }
+        if ranges.clone().any(LockingRange::is_locked) {
+            flags |= LockingFlags::LOCKED;
+        }
+        if self.mbr.enable {
+            flags |= LockingFlags::MBR_ENABLED;
+        }
+        if self.mbr.done {
+            flags |= LockingFlags::MBR_DONE;
+        }
+        flags
+    }
+
+    fn level0_discovery(&self) -> Vec<u8> {
+        let mut out = vec![0; 48];
+        out[4..8].copy_from_slice(&1u32.to_be_bytes());
+
+        let mut feature = |code: FeatureCodes, version: u8, data: &[u8]| {
+            out.extend(code.0.to_be_bytes());
+            out.push(version << 4);
+            out.push(data.len() as u8);
+            out.extend(data);
+        };
+
+        // sync, streaming, ComID management
+        feature(FeatureCodes::TPER, 1, &[0x51, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        feature(FeatureCodes::LOCKING, 1, &[self.locking_flags().bits(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut geometry = vec![0; 28];
+        geometry[0] = 1;
+        geometry[8..12].copy_from_slice(&512u32.to_be_bytes());
+        geometry[12..20].copy_from_slice(&8u64.to_be_bytes());
+        feature(FeatureCodes::GEOMETRY, 1, &geometry);
+
+        if self.block_sid_supported {
+            let sid_value_changed = self.c_pins.get(&C_PIN_SID).is_some_and(|c_pin| c_pin.pin != self.msid);
+            let mut block_sid = vec![0; 12];
+            block_sid[0] = sid_value_changed as u8 | (self.sid_blocked as u8) << 1;
+            block_sid[1] = self.block_sid_hardware_reset as u8;
+            feature(FeatureCodes::BLOCK_SID, 1, &block_sid);
+        }
+
+        let mut datastore = vec![0; 12];
+        datastore[2..4].copy_from_slice(&1u16.to_be_bytes());
+        datastore[4..8].copy_from_slice(&(DATASTORE_SIZE as u32).to_be_bytes());
+        datastore[8..12].copy_from_slice(&1u32.to_be_bytes());
+        feature(FeatureCodes::DATASTORE, 1, &datastore);
+
+        if self.single_user_supported && self.ssc != Ssc::Enterprise {
+            let any = self.ranges.iter().any(|r| r.single_user);
+            let all = self.ranges.iter().all(|r| r.single_user);
+            let mut single_user = vec![0; 12];
+            single_user[0..4].copy_from_slice(&(self.ranges.len() as u32).to_be_bytes());
+            single_user[4] = any as u8 | (all as u8) << 1 | (self.single_user_policy as u8) << 2;
+            feature(FeatureCodes::SINGLE_USER, 1, &single_user);
+        }
+
+        if self.ssc == Ssc::Enterprise {
+            let mut enterprise = vec![0; 12];
+            enterprise[0..2].copy_from_slice(&ENTERPRISE_COM_ID.to_be_bytes());
+            enterprise[2..4].copy_from_slice(&1u16.to_be_bytes());
+            feature(FeatureCodes::ENTERPRISE, 1, &enterprise);
+            let len = out.len() as u32 - 4;
+            out[0..4].copy_from_slice(&len.to_be_bytes());
+            return out;
+        }
+
+        let mut ssc = vec![0; 16];
+        ssc[0..2].copy_from_slice(&BASE_COM_ID.to_be_bytes());
+        ssc[2..4].copy_from_slice(&1u16.to_be_bytes());
+        match self.ssc {
+            // no numbers of admins / users and initial PINs yet
+            Ssc::OpalV1 => feature(FeatureCodes::OPAL_V1, 1, &ssc[..12]),
+            // initial PINs, but a fixed number of admins / users
+            Ssc::PyriteV1 => feature(FeatureCodes::PYRITE_V1, 1, &ssc),
+            Ssc::PyriteV2 => feature(FeatureCodes::PYRITE_V2, 1, &ssc),
+            Ssc::OpalV2 | Ssc::Ruby => {
+                ssc[5..7].copy_from_slice(&(NUM_LOCKING_ADMINS as u16).to_be_bytes());
+                ssc[7..9].copy_from_slice(&(NUM_LOCKING_USERS as u16).to_be_bytes());
+                match self.ssc {
+                    Ssc::Ruby => feature(FeatureCodes::RUBY, 1, &ssc),
+                    _ => feature(FeatureCodes::OPAL_V2, 2, &ssc),
+                }
+            }
+            Ssc::Enterprise => unreachable!(),
+        }
+
+        let len = out.len() as u32 - 4;
+        out[0..4].copy_from_slice(&len.to_be_bytes());
+        out
+    }
+
+    fn handle_com_packet(&mut self, data: &[u8]) {
+        let Some(header) = data.get(..HEADER_LEN) else { return };
+        let be32 = |offset: usize| u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+        if be32(16) as usize + COM_PACKET_HEADER_LEN > self.max_com_packet_size as usize {
+            tracing::warn!("simulator drops a ComPacket larger than MaxComPacketSize");
+            return;
+        }
+        let tsn = be32(20);
+        let hsn = be32(24);
+        let subpkt_len = be32(52) as usize;
+        let Some(payload) = data.get(HEADER_LEN..HEADER_LEN + subpkt_len) else { return };
+
+        let Some(values) = Parser::new(payload).parse_all() else {
+            tracing::warn!("simulator could not parse payload {payload:02X?}");
+            return;
+        };
+
+        let response = match values.as_slice() {
+            [Value::Control(t)] if *t == token::ENDOFSESSION.token => {
+                match &self.session {
+                    Some(s) if s.tsn == tsn && s.hsn == hsn => self.end_session(),
+                    _ => return,
+                }
+                vec![Value::Control(token::ENDOFSESSION.token)]
+            }
+            [Value::Control(t), Value::Uint(status)] if [token::STARTTRANSACTON.token, token::ENDTRANSACTON.token].contains(t) => {
+                let open = match &mut self.session {
+                    Some(s) if s.tsn == tsn && s.hsn == hsn => s.transaction.take(),
+                    _ => return,
+                };
+                let (status, transaction) = match (*t == token::STARTTRANSACTON.token, open) {
+                    // no nested transactions
+                    (true, Some(snapshot)) => (1, Some(snapshot)),
+                    (true, None) => (0, Some(Box::new(self.snapshot()))),
+                    (false, None) => (1, None),
+                    (false, Some(_)) if *status == 0 => (0, None),
+                    (false, Some(snapshot)) => {
+                        self.restore(*snapshot);
+                        (1, None)
+                    }
+                };
+                self.session.as_mut().unwrap().transaction = transaction;
+                vec![Value::Control(*t), Value::Uint(status)]
+            }
+            [Value::Control(call), Value::Bytes(object), Value::Bytes(method), Value::List(args), Value::Control(eod), Value::List(_)]
+                if *call == token::CALL.token && *eod == token::ENDOFDATA.token =>
+            {
+                let (Some(object), Some(method)) = (bytes_to_uid(object), bytes_to_uid(method)) else { return };
+                if tsn == 0 && hsn == 0 {
+                    self.call_session_manager(object, method, args)
+                } else {
+                    match &self.session {
+                        Some(s) if s.tsn == tsn && s.hsn == hsn => {
+                            self.method_calls += 1;
+                            method_response(self.call(object, method, args))
+                        }
+                        _ => return,
+                    }
+                }
+            }
+            _ => return,
+        };
+
+        let mut response = frame(self.com_id(), tsn, hsn, &response);
+        // responses have to fit what both sides announced with Properties
+        let host_limit = self.host_property("MaxComPacketSize").unwrap_or(u64::MAX);
+        if response.len() as u64 > host_limit.min(self.max_com_packet_size as u64) {
+            response = frame(self.com_id(), tsn, hsn, &method_response(Err(StatusCode::RESPONSE_OVERFLOW)));
+        }
+        if let Some(corrupt) = self.corrupt {
+            corrupt(&mut response);
+        }
+        self.responses = match self.split {
+            Some(max_payload) => split(&response, max_payload),
+            None => VecDeque::from([response]),
+        };
+    }
+
+    /// Closes the session, aborting its open transaction
+    fn end_session(&mut self) {
+        if let Some(snapshot) = self.session.take().and_then(|s| s.transaction) {
+            self.restore(*snapshot);
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            locking_sp_active: self.locking_sp_active,
+            c_pins: self.c_pins.clone(),
+            authorities: self.authorities.clone(),
+            ranges: self.ranges.clone(),
+            mbr: self.mbr.clone(),
+            datastore: self.datastore.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        // failed authentications count even if the transaction is aborted
+        let tries: Vec<_> = self.c_pins.iter().map(|(&c_pin, row)| (c_pin, row.tries)).collect();
+        self.locking_sp_active = snapshot.locking_sp_active;
+        self.c_pins = snapshot.c_pins;
+        self.authorities = snapshot.authorities;
+        self.ranges = snapshot.ranges;
+        self.mbr = snapshot.mbr;
+        self.datastore = snapshot.datastore;
+        for (c_pin, tries) in tries {
+            if let Some(row) = self.c_pins.get_mut(&c_pin) {
+                row.tries = tries;
+            }
+        }
+    }
+
+    fn call_session_manager(&mut self, object: u64, method: u64, args: &[Value]) -> Vec<Value> {
+        let result = if object != uid(uid::OPAL_SMUID) {
+            Err(StatusCode::INVALID_PARAMETER)
+        } else if method == uid(method::STARTSESSION) {
+            self.start_session(args).map(|(hsn, tsn)| {
+                (method::SYNCSESSION, vec![Value::Uint(hsn as u64), Value::Uint(tsn as u64)])
+            })
+        } else if method == uid(method::PROPERTIES) {
+            self.properties(args).map(|results| (method::PROPERTIES, results))
+        } else {
+            Err(StatusCode::INVALID_PARAMETER)
+        };
+        let (reply, results) = match result {
+            Ok(reply) => reply,
+            Err(status) => return method_response(Err(status)),
+        };
+        let mut response = vec![
+            Value::Control(token::CALL.token),
+            Value::Bytes(uid::OPAL_SMUID.bytes.to_vec()),
+            Value::Bytes(reply.bytes.to_vec()),
+        ];
+        response.extend(method_response(Ok(results)));
+        response
+    }
+
+    /// `[ HostProperties = [ name = value ... ] ]` to `[ [ TPer properties ] HostProperties = [ ... ] ]`
+    fn properties(&mut self, args: &[Value]) -> Result<Vec<Value>, StatusCode> {
+        let host = match args {
+            [] => vec![],
+            [Value::Name(name, host)] if **name == Value::Uint(0) => match &**host {
+                Value::List(host) => host.clone(),
+                _ => return Err(StatusCode::INVALID_PARAMETER),
+            },
+            _ => return Err(StatusCode::INVALID_PARAMETER),
+        };
+        for value in &host {
+            match value {
+                Value::Name(name, value) if matches!((&**name, &**value), (Value::Bytes(_), Value::Uint(_))) => {}
+                _ => return Err(StatusCode::INVALID_PARAMETER),
+            }
+        }
+        let max_com_packet_size = self.max_com_packet_size as u64;
+        let tper = [
+            ("MaxMethods", 1),
+            ("MaxSubpackets", 1),
+            ("MaxPacketSize", max_com_packet_size - COM_PACKET_HEADER_LEN as u64),
+            ("MaxPackets", 1),
+            ("MaxComPacketSize", max_com_packet_size),
+            ("MaxResponseComPacketSize", max_com_packet_size),
+            ("MaxSessions", 1),
+            ("MaxIndTokenSize", max_com_packet_size - HEADER_LEN as u64),
+            ("MaxAggTokenSize", max_com_packet_size - HEADER_LEN as u64),
+            ("MaxAuthentications", 2),
+            ("MaxTransactionLimit", 1),
+            ("DefSessionTimeout", 0),
+        ];
+        let tper = tper.into_iter()
+            .map(|(name, value)| Value::Name(Box::new(Value::Bytes(name.as_bytes().to_vec())), Box::new(Value::Uint(value))))
+            .collect();
+        self.host_properties = host.clone();
+        Ok(vec![Value::List(tper), Value::Name(Box::new(Value::Uint(0)), Box::new(Value::List(host)))])
+    }
+
+    fn start_session(&mut self, args: &[Value]) -> Result<(u32, u32), StatusCode> {
+        let [Value::Uint(hsn), Value::Bytes(sp), Value::Uint(write), optional @ ..] = args else {
+            return Err(StatusCode::INVALID_PARAMETER);
+        };
+        let sp = match bytes_to_uid(sp) {
+            Some(sp) if sp == uid(uid::OPAL_ADMINSP) => Sp::Admin,
+            Some(sp) if sp == uid(uid::OPAL_LOCKINGSP) && self.locking_sp_active && self.ssc != Ssc::Enterprise => Sp::Locking,
+            Some(sp) if sp == uid(uid::ENTERPRISE_LOCKINGSP) && self.ssc == Ssc::Enterprise => Sp::Locking,
+            _ => return Err(StatusCode::INVALID_PARAMETER),
+        };
+        if self.session.is_some() {
+            return Err(StatusCode::SP_BUSY);
+        }
+
+        let mut challenge = None;
+        let mut authority = ANYBODY;
+        for value in optional {
+            match value {
+                Value::Name(name, value) => match (&**name, &**value) {
+                    (Value::Uint(0), Value::Bytes(c)) => challenge = Some(c.as_slice()),
+                    (Value::Uint(3), Value::Bytes(a)) => authority = bytes_to_uid(a).ok_or(StatusCode::INVALID_PARAMETER)?,
+                    _ => {}
+                },
+                _ => return Err(StatusCode::INVALID_PARAMETER),
+            }
+        }
+
+        if authority != ANYBODY {
+            self.authenticate(sp, authority, challenge.unwrap_or_default())?;
+        }
+
+        let tsn = self.next_tsn;
+        self.next_tsn += 1;
+        let hsn = *hsn as u32;
+        let authorities = vec![ANYBODY, authority];
+        self.session = Some(Session { tsn, hsn, sp, write: *write != 0, authorities, transaction: None });
+        Ok((hsn, tsn))
+    }
+
+    fn authenticate(&mut self, sp: Sp, authority: u64, challenge: &[u8]) -> Result<(), StatusCode> {
+        if authority == SID && self.sid_blocked {
+            return Err(StatusCode::NOT_AUTHORIZED);
+        }
+        let auth = match self.authorities.get(&authority) {
+            Some(auth) if auth.sp == sp && auth.enabled => auth,
+            _ => return Err(StatusCode::NOT_AUTHORIZED),
+        };
+        let c_pin = self.c_pins.get_mut(&auth.c_pin).unwrap();
+        if c_pin.try_limit != 0 && c_pin.tries >= c_pin.try_limit {
+            return Err(StatusCode::AUTHORITY_LOCKED_OUT);
+        }
+        // the host sends an empty PIN as the null token, a single zero byte
+        let challenge = match challenge {
+            [0] => &[][..],
+            challenge => challenge,
+        };
+        if c_pin.pin != challenge {
+            c_pin.tries += 1;
+            return Err(StatusCode::NOT_AUTHORIZED);
+        }
+        c_pin.tries = 0;
+        Ok(())
+    }
+
+    fn session_is_admin(&self) -> bool {
+        let session = self.session.as_ref().unwrap();
+        session.authorities.iter().any(|a| self.authorities.get(a).is_some_and(|a| a.admin))
+    }
+
+    fn session_user(&self) -> Option<u8> {
+        let session = self.session.as_ref().unwrap();
+        if session.sp != Sp::Locking || self.ssc == Ssc::Enterprise {
+            return None;
+        }
+        session.authorities.iter().find_map(|a| match a.checked_sub(LOCKING_USER_BASE) {
+            Some(n @ 1..=0xFF) => Some(n as u8),
+            _ => None,
+        })
+    }
+
+    fn session_has(&self, authority: u64) -> bool {
+        self.session.as_ref().unwrap().authorities.contains(&authority)
+    }
+
+    /// whether the session may access column `col` of locking range `n`; `None` checks row access
+    fn may_access_range(&self, n: u8, col: Option<u64>) -> bool {
+        let user_allowed = self.session_user().is_some_and(|u| self.ranges[n as usize].users.contains(&u));
+        if self.ranges[n as usize].single_user {
+            // the admins may only read the row and set its bounds, the user only sets them unless the policy forbids it
+            return match col {
+                Some(3 | 4) if self.single_user_policy => self.session_is_admin(),
+                Some(3 | 4) => user_allowed || self.session_is_admin(),
+                Some(_) => user_allowed,
+                None => user_allowed || self.session_is_admin(),
+            };
+        }
+        let band_master = self.ssc == Ssc::Enterprise && self.session_has(BAND_MASTER_BASE + n as u64);
+        // like ACE_Locking_Range*_Set_RdLocked / _WrLocked, the Get of a row is Admins only
+        self.session_is_admin() || band_master || (user_allowed && col.is_some_and(|c| c == 7 || c == 8))
+    }
+
+    fn call(&mut self, object: u64, method: u64, args: &[Value]) -> Result<Vec<Value>, StatusCode> {
+        let eprise = self.ssc == Ssc::Enterprise;
+        if method == uid(method::AUTHENTICATE) && self.ssc == Ssc::OpalV1 {
+            // like Opal 1.0 firmware without it
+            return Err(StatusCode::INVALID_FUNCTION);
+        }
+        if (method == uid(method::AUTHENTICATE) && !eprise) || (method == uid(method::EAUTHENTICATE) && eprise) {
+            if object != uid(uid::OPAL_THISSP) {
+                return Err(StatusCode::INVALID_PARAMETER);
+            }
+            let [Value::Bytes(authority), Value::Name(name, challenge)] = args else {
+                return Err(StatusCode::INVALID_PARAMETER);
+            };
+            let challenge = match (&**name, &**challenge) {
+                (Value::Uint(0), Value::Bytes(c)) if !eprise => c,
+                (Value::Bytes(n), Value::Bytes(c)) if eprise && n == b"Challenge" => c,
+                _ => return Err(StatusCode::INVALID_PARAMETER),
+            };
+            let authority = bytes_to_uid(authority).ok_or(StatusCode::INVALID_PARAMETER)?;
+            let sp = self.session.as_ref().unwrap().sp;
+            return match self.authenticate(sp, authority, challenge) {
+                Ok(()) => {
+                    self.session.as_mut().unwrap().authorities.push(authority);
+                    Ok(vec![Value::Uint(1)])
+                }
+                Err(StatusCode::NOT_AUTHORIZED) => Ok(vec![Value::Uint(0)]),
+                Err(status) => Err(status),
+            };
+        }
+        if method == uid(method::RANDOM) {
+            return self.random(object, args);
+        }
+        if method == uid(method::ACTIVATE) {
+            return self.activate(object, args);
+        }
+        if method == uid(method::REVERT) {
+            return self.revert(object);
+        }
+        if method == uid(method::REVERTSP) && !eprise {
+            return self.revert_sp(object);
+        }
+        if eprise {
+            return self.call_enterprise(object, method, args);
+        }
+
+        if object == DATASTORE && (method == uid(method::GET) || method == uid(method::SET)) {
+            return self.call_datastore(method, args);
+        }
+        if method == uid(method::GET) {
+            let (start, end) = parse_cellblock(args)?;
+            let row = self.get(object)?;
+            Ok(vec![Value::List(
+                row.into_iter()
+                    .filter(|(col, _)| (start..=end).contains(col))
+                    .map(|(col, value)| Value::Name(Box::new(Value::Uint(col)), Box::new(value)))
+                    .collect(),
+            )])
+        } else if method == uid(method::SET) {
+            if !self.session.as_ref().unwrap().write {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            let values = parse_values(args)?;
+            self.set(object, &values)?;
+            Ok(vec![])
+        } else if method == uid(method::GENKEY) {
+            // no keys without media encryption
+            let n = self.key_index(object).filter(|_| self.media_encryption()).ok_or(StatusCode::INVALID_PARAMETER)?;
+            if !self.session.as_ref().unwrap().write || !self.session_is_admin() {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            self.ranges[n as usize].key_generation += 1;
+            Ok(vec![])
+        } else {
+            Err(StatusCode::INVALID_PARAMETER)
+        }
+    }
+
+    /// `Get [ [ startRow = a, endRow = b ] ]` and `Set [ Where = a, Values = bytes ]` of the byte table
+    fn call_datastore(&mut self, method: u64, args: &[Value]) -> Result<Vec<Value>, StatusCode> {
+        let session = self.session.as_ref().unwrap();
+        if session.sp != Sp::Locking || !self.session_is_admin() {
+            return Err(StatusCode::NOT_AUTHORIZED);
+        }
+        if method == uid(method::GET) {
+            let [Value::List(cellblock)] = args else { return Err(StatusCode::INVALID_PARAMETER) };
+            let [Value::Name(start_name, start), Value::Name(end_name, end)] = cellblock.as_slice() else {
+                return Err(StatusCode::INVALID_PARAMETER);
+            };
+            let (Value::Uint(1), Value::Uint(start), Value::Uint(2), Value::Uint(end)) = (&**start_name, &**start, &**end_name, &**end) else {
+                return Err(StatusCode::INVALID_PARAMETER);
+            };
+            match self.datastore.get(*start as usize..=*end as usize) {
+                Some(bytes) => Ok(vec![Value::Bytes(bytes.to_vec())]),
+                None => Err(StatusCode::INVALID_PARAMETER),
+            }
+        } else {
+            if !session.write {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            let [Value::Name(where_name, start), Value::Name(values_name, bytes)] = args else {
+                return Err(StatusCode::INVALID_PARAMETER);
+            };
+            let (Value::Uint(0), Value::Uint(start), Value::Uint(1), Value::Bytes(bytes)) = (&**where_name, &**start, &**values_name, &**bytes) else {
+                return Err(StatusCode::INVALID_PARAMETER);
+            };
+            match self.datastore.get_mut(*start as usize..*start as usize + bytes.len()) {
+                Some(target) => target.copy_from_slice(bytes),
+                None => return Err(StatusCode::INVALID_PARAMETER),
+            }
+            Ok(vec![])
+        }
+    }
+
+    /// [ Count ] of at most 32 bytes
+    fn random(&mut self, object: u64, args: &[Value]) -> Result<Vec<Value>, StatusCode> {
+        let &[Value::Uint(count)] = args else { return Err(StatusCode::INVALID_PARAMETER) };
+        if object != uid(uid::OPAL_THISSP) || count > 32 {
+            return Err(StatusCode::INVALID_PARAMETER);
+        }
+        let random = (0..count).map(|_| {
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 7;
+            self.random ^= self.random << 17;
+            self.random as u8
+        }).collect();
+        Ok(vec![Value::Bytes(random)])
+    }
+
+    /// [ SingleUserSelectionList = [ range uids ] | Locking table uid, RangeStartRangeLengthPolicy = 0 | 1 ], both optional
+    fn activate(&mut self, object: u64, args: &[Value]) -> Result<Vec<Value>, StatusCode> {
+        if self.session.as_ref().unwrap().sp != Sp::Admin || !self.session_has(SID) {
+            return Err(StatusCode::NOT_AUTHORIZED);
+        }
+        if object != uid(uid::OPAL_LOCKINGSP) || self.ssc == Ssc::Enterprise {
+            return Err(StatusCode::INVALID_PARAMETER);
+        }
+
+        let mut single_user = Vec::new();
+        let mut policy = false;
+        for value in args {
+            let Value::Name(name, value) = value else { return Err(StatusCode::INVALID_PARAMETER) };
+            match (&**name, &**value) {
+                (Value::Uint(0x06_0000), _) if !self.single_user_supported => return Err(StatusCode::INVALID_PARAMETER),
+                (Value::Uint(0x06_0000), Value::Bytes(table)) if bytes_to_uid(table) == Some(uid(uid::OPAL_LOCKING_TABLE)) => {
+                    single_user = (0..self.ranges.len() as u8).collect();
+                }
+                (Value::Uint(0x06_0000), Value::List(ranges)) => {
+                    for range in ranges {
+                        let Value::Bytes(range) = range else { return Err(StatusCode::INVALID_PARAMETER) };
+                        let n = bytes_to_uid(range).and_then(|r| self.range_index(r)).ok_or(StatusCode::INVALID_PARAMETER)?;
+                        single_user.push(n);
+                    }
+                }
+                (Value::Uint(0x06_0001), Value::Uint(p)) => policy = *p != 0,
+                _ => return Err(StatusCode::INVALID_PARAMETER),
+            }
+        }
+
+        // activating an active SP does nothing
+        if !self.locking_sp_active {
+            self.locking_sp_active = true;
+            let sid = self.c_pins[&C_PIN_SID].pin.clone();
+            self.set_admin_pin(1, &sid);
+            self.single_user_policy = policy;
+            // the user of each range is enabled and keeps its empty password
+            for n in single_user {
+                let range = &mut self.ranges[n as usize];
+                range.single_user = true;
+                range.users = vec![n + 1];
+                self.authorities.get_mut(&(LOCKING_USER_BASE + n as u64 + 1)).unwrap().enabled = true;
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn revert(&mut self, object: u64) -> Result<Vec<Value>, StatusCode> {
+        if self.session.as_ref().unwrap().sp != Sp::Admin || !(self.session_has(SID) || self.session_has(PSID_AUTHORITY)) {
+            return Err(StatusCode::NOT_AUTHORIZED);
+        }
+        if object != uid(uid::OPAL_ADMINSP) {
+            return Err(StatusCode::INVALID_PARAMETER);
+        }
+        let factory = self.factory_state();
+        self.locking_sp_active = factory.locking_sp_active;
+        self.c_pins = factory.c_pins;
+        self.authorities = factory.authorities;
+        self.ranges = factory.ranges;
+        self.mbr = factory.mbr;
+        self.datastore = factory.datastore;
+        self.session = None;
+        Ok(vec![])
+    }
+
+    fn revert_sp(&mut self, object: u64) -> Result<Vec<Value>, StatusCode> {
+        if self.session.as_ref().unwrap().sp != Sp::Locking || !self.session_is_admin() {
+            return Err(StatusCode::NOT_AUTHORIZED);
+        }
+        if object != uid(uid::OPAL_THISSP) {
+            return Err(StatusCode::INVALID_PARAMETER);
+        }
+        let mut factory = self.factory_state();
+        for (authority, auth) in factory.authorities.into_iter().filter(|(_, a)| a.sp == Sp::Locking) {
+            self.c_pins.insert(auth.c_pin, factory.c_pins.remove(&auth.c_pin).unwrap());
+            self.authorities.insert(authority, auth);
+        }
+        self.locking_sp_active = false;
+        self.ranges = factory.ranges;
+        self.mbr = factory.mbr;
+        self.datastore = factory.datastore;
+        self.session = None;
+        Ok(vec![])
+    }
+
+    /// The drive as it left the factory, with the same MSID
+    fn factory_state(&self) -> Self {
+        let mut factory = match self.ssc {
+            Ssc::OpalV1 | Ssc::OpalV2 | Ssc::PyriteV1 | Ssc::PyriteV2 | Ssc::Ruby => {
+                let mut factory = Self::new(&self.serial);
+                factory.ssc = self.ssc;
+                factory.ranges.truncate(self.ranges.len());
+                factory
+            }
+            // all BandMasters use MSID and no band is locking
+            Ssc::Enterprise => {
+                let mut factory = Self::enterprise(&self.serial, DEFAULT_MSID);
+                factory.ranges[0] = LockingRange::default();
+                factory
+            }
+        };
+        factory.set_msid(&self.msid);
+        factory
+    }
+
+    fn call_enterprise(&mut self, object: u64, method: u64, args: &[Value]) -> Result<Vec<Value>, StatusCode> {
+        let column = |name: &[u8]| ENTERPRISE_COLUMNS.iter().find(|(n, _)| *n == name).map(|&(_, col)| col);
+        if method == uid(method::EGET) {
+            // [ [ startColumn = "name", endColumn = "name" ] ]
+            let [Value::List(cellblock)] = args else { return Err(StatusCode::INVALID_PARAMETER) };
+            let mut start = 0;
+            let mut end = u64::MAX;
+            for value in cellblock {
+                let Value::Name(name, value) = value else { return Err(StatusCode::INVALID_PARAMETER) };
+                let (Value::Bytes(name), Value::Bytes(col)) = (&**name, &**value) else {
+                    return Err(StatusCode::INVALID_PARAMETER);
+                };
+                let col = column(col).ok_or(StatusCode::INVALID_PARAMETER)?;
+                match name.as_slice() {
+                    b"startColumn" => start = col,
+                    b"endColumn" => end = col,
+                    _ => return Err(StatusCode::INVALID_PARAMETER),
+                }
+            }
+            let row = self.get(object)?;
+            let named = row.into_iter()
+                .filter(|(col, _)| (start..=end).contains(col))
+                .filter_map(|(col, value)| {
+                    let &(name, _) = ENTERPRISE_COLUMNS.iter().find(|&&(_, c)| c == col)?;
+                    Some(Value::Name(Box::new(Value::Bytes(name.to_vec())), Box::new(value)))
+                })
+                .collect();
+            Ok(vec![Value::List(vec![Value::List(named)])])
+        } else if method == uid(method::ESET) {
+            if !self.session.as_ref().unwrap().write {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            // [ [ ] [ [ "name" = value, ... ] ] ]
+            let [Value::List(_where), Value::List(values)] = args else { return Err(StatusCode::INVALID_PARAMETER) };
+            let [Value::List(values)] = values.as_slice() else { return Err(StatusCode::INVALID_PARAMETER) };
+            let values = values.iter()
+                .map(|value| match value {
+                    Value::Name(name, value) => match &**name {
+                        Value::Bytes(name) => Ok((column(name).ok_or(StatusCode::INVALID_PARAMETER)?, &**value)),
+                        _ => Err(StatusCode::INVALID_PARAMETER),
+                    },
+                    _ => Err(StatusCode::INVALID_PARAMETER),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            self.set(object, &values)?;
+            Ok(vec![])
+        } else if method == uid(method::ERASE) {
+            let n = self.range_index(object).ok_or(StatusCode::INVALID_PARAMETER)?;
+            if !self.session.as_ref().unwrap().write || !self.session_has(ERASE_MASTER) {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            // new key, locking disabled and the BandMaster back to MSID
+            let range = &mut self.ranges[n as usize];
+            *range = LockingRange {
+                start: range.start,
+                length: range.length,
+                key_generation: range.key_generation + 1,
+                ..LockingRange::default()
+            };
+            let msid = self.msid.clone();
+            self.set_band_master_pin(n, &msid);
+            Ok(vec![])
+        } else {
+            Err(StatusCode::INVALID_PARAMETER)
+        }
+    }
+
+    fn get(&self, object: u64) -> Result<Vec<(u64, Value)>, StatusCode> {
+        let sp = self.session.as_ref().unwrap().sp;
+        let admin = self.session_is_admin();
+        let bool_value = |b: bool| Value::Uint(b as u64);
+
+        if sp == Sp::Admin && object == C_PIN_MSID {
+            return Ok(vec![(3, Value::Bytes(self.msid.clone()))]);
+        }
+        if let Some(c_pin) = self.c_pins.get(&object) {
+            if self.c_pin_sp(object) != Some(sp) {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            if !admin {
+                if !self.public_tries {
+                    return Err(StatusCode::NOT_AUTHORIZED);
+                }
+                return Ok(vec![
+                    (5, Value::Uint(c_pin.try_limit as u64)),
+                    (6, Value::Uint(c_pin.tries as u64)),
+                ]);
+            }
+            // the PIN column is never readable
+            return Ok(vec![
+                (0, Value::Bytes(object.to_be_bytes().to_vec())),
+                (5, Value::Uint(c_pin.try_limit as u64)),
+                (6, Value::Uint(c_pin.tries as u64)),
+                (7, bool_value(false)),
+            ]);
+        }
+        if sp != Sp::Locking {
+            return Err(StatusCode::INVALID_PARAMETER);
+        }
+        if let Some(n) = self.range_index(object) {
+            let range = &self.ranges[n as usize];
+            if !self.may_access_range(n, None) {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            let lock_on_reset = match range.lock_on_reset {
+                true => vec![Value::Uint(0)],
+                false => vec![],
+            };
+            return Ok(vec![
+                (0, Value::Bytes(object.to_be_bytes().to_vec())),
+                (3, Value::Uint(range.start)),
+                (4, Value::Uint(range.length)),
+                (5, bool_value(range.read_lock_enabled)),
+                (6, bool_value(range.write_lock_enabled)),
+                (7, bool_value(range.read_locked)),
+                (8, bool_value(range.write_locked)),
+                (9, Value::List(lock_on_reset)),
+                (10, Value::Bytes(self.key_uid(n).to_be_bytes().to_vec())),
+            ]);
+        }
+        if object == MBR_CONTROL && self.mbr_shadowing() {
+            return Ok(vec![
+                (0, Value::Bytes(object.to_be_bytes().to_vec())),
+                (1, bool_value(self.mbr.enable)),
+                (2, bool_value(self.mbr.done)),
+                (3, Value::List(match self.mbr.done_on_reset {
+                    true => vec![Value::Uint(0)],
+                    false => vec![],
+                })),
+            ]);
+        }
+        Err(StatusCode::INVALID_PARAMETER)
+    }
+
+    fn set(&mut self, object: u64, values: &[(u64, &Value)]) -> Result<(), StatusCode> {
+        let session = self.session.as_ref().unwrap();
+        let sp = session.sp;
+        let admin = self.session_is_admin();
+
+        if self.c_pins.contains_key(&object) {
+            let own = session.authorities.iter().any(|a| self.authorities.get(a).is_some_and(|a| a.c_pin == object));
+            // the users of Single User Mode ranges are the only ones to set their password
+            let single_user = self.ranges.iter().enumerate()
+                .any(|(n, r)| r.single_user && object == C_PIN_LOCKING_USER_BASE + n as u64 + 1);
+            if object == C_PIN_MSID || object == C_PIN_PSID || self.c_pin_sp(object) != Some(sp) || !((admin && !single_user) || own) {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            for &(col, value) in values {
+                match (col, value) {
+                    (3, Value::Bytes(pin)) => self.c_pins.get_mut(&object).unwrap().pin = pin.clone(),
+                    _ => return Err(StatusCode::INVALID_PARAMETER),
+                }
+            }
+            return Ok(());
+        }
+        if sp != Sp::Locking {
+            return Err(StatusCode::INVALID_PARAMETER);
+        }
+        if let Some(n) = self.range_index(object) {
+            if !values.iter().all(|&(col, _)| self.may_access_range(n, Some(col))) {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            let range = &mut self.ranges[n as usize];
+            for &(col, value) in values {
+                match (col, value) {
+                    (3 | 4, _) if n == 0 => return Err(StatusCode::INVALID_PARAMETER),
+                    (3, Value::Uint(v)) => range.start = *v,
+                    (4, Value::Uint(v)) => range.length = *v,
+                    (5, Value::Uint(v)) => range.read_lock_enabled = *v != 0,
+                    (6, Value::Uint(v)) => range.write_lock_enabled = *v != 0,
+                    (7, Value::Uint(v)) => range.read_locked = *v != 0,
+                    (8, Value::Uint(v)) => range.write_locked = *v != 0,
+                    (9, Value::List(l)) => range.lock_on_reset = !l.is_empty(),
+                    _ => return Err(StatusCode::INVALID_PARAMETER),
+                }
+            }
+            return Ok(());
+        }
+        if object == MBR_CONTROL && self.mbr_shadowing() {
+            if !admin {
+                return Err(StatusCode::NOT_AUTHORIZED);
+            }
+            for &(col, value) in values {
+                match (col, value) {
+                    (1, Value::Uint(v)) => self.mbr.enable = *v != 0,
+                    (2, Value::Uint(v)) => self.mbr.done = *v != 0,
+                    (3, Value::List(l)) => self.mbr.done_on_reset = !l.is_empty(),
+                    _ => return Err(StatusCode::INVALID_PARAMETER),
+                }
+            }
+            return Ok(());
+        }
+        Err(StatusCode::INVALID_PARAMETER)
+    }
+
+    fn range_index(&self, object: u64) -> Option<u8> {
+        let n = match self.ssc {
+            Ssc::Enterprise => object.checked_sub(LOCKING_GLOBAL_RANGE)?,
+            _ if object == LOCKING_GLOBAL_RANGE => 0,
+            _ => object.checked_sub(LOCKING_RANGE_BASE).filter(|&n| n != 0)?,
+        };
+        match n < self.ranges.len() as u64 {
+            true => Some(n as u8),
+            false => None,
+        }
+    }
+
+    fn key_uid(&self, n: u8) -> u64 {
+        match n {
+            0 => K_AES_256_GLOBAL_RANGE,
+            n => K_AES_256_RANGE_BASE + n as u64,
+        }
+    }
+
+    fn key_index(&self, object: u64) -> Option<u8> {
+        (0..self.ranges.len() as u8).find(|&n| self.key_uid(n) == object)
+    }
+
+    fn media_encryption(&self) -> bool {
+        !matches!(self.ssc, Ssc::PyriteV1 | Ssc::PyriteV2)
+    }
+
+    fn mbr_shadowing(&self) -> bool {
+        !matches!(self.ssc, Ssc::Enterprise | Ssc::Ruby)
+    }
+
+    fn c_pin_sp(&self, c_pin: u64) -> Option<Sp> {
+        match c_pin {
+            C_PIN_SID | C_PIN_MSID | C_PIN_PSID | C_PIN_ADMIN_SP_ADMIN1 => Some(Sp::Admin),
+            _ => self.authorities.values().find(|a| a.c_pin == c_pin).map(|a| a.sp),
+        }
+    }
+}
+
+
+fn bytes_to_uid(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// `[ [ startColumn = x, endColumn = y ] ]`, both optional
+fn parse_cellblock(args: &[Value]) -> Result<(u64, u64), StatusCode> {
+    let [Value::List(cellblock)] = args else { return Err(StatusCode::INVALID_PARAMETER) };
+    let mut start = 0;
+    let mut end = u64::MAX;
+    for value in cellblock {
+        match value {
+            Value::Name(name, value) => match (&**name, &**value) {
+                (Value::Uint(3), Value::Uint(v)) => start = *v,
+                (Value::Uint(4), Value::Uint(v)) => end = *v,
+                _ => return Err(StatusCode::INVALID_PARAMETER),
+            },
+            _ => return Err(StatusCode::INVALID_PARAMETER),
+        }
+    }
+    Ok((start, end))
+}
+
+/// `[ Values = [ col = value, ... ] ]`
+fn parse_values(args: &[Value]) -> Result<Vec<(u64, &Value)>, StatusCode> {
+    let [Value::Name(name, values)] = args else { return Err(StatusCode::INVALID_PARAMETER) };
+    let (Value::Uint(1), Value::List(values)) = (&**name, &**values) else {
+        return Err(StatusCode::INVALID_PARAMETER);
+    };
+    values.iter()
+        .map(|value| match value {
+            Value::Name(name, value) => match &**name {
+                Value::Uint(col) => Ok((*col, &**value)),
+                _ => Err(StatusCode::INVALID_PARAMETER),
+            },
+            _ => Err(StatusCode::INVALID_PARAMETER),
+        })
+        .collect()
+}
+
+fn method_response(result: Result<Vec<Value>, StatusCode>) -> Vec<Value> {
+    let (results, status) = match result {
+        Ok(results) => (results, StatusCode::SUCCESS),
+        Err(status) => (vec![], status),
+    };
+    vec![
+        Value::List(results),
+        Value::Control(token::ENDOFDATA.token),
+        Value::List(vec![Value::Uint(status.0 as u64), Value::Uint(0), Value::Uint(0)]),
+    ]
+}
+
+/// Wraps the response tokens into a ComPacket / Packet / Subpacket.
+fn frame(com_id: u16, tsn: u32, hsn: u32, values: &[Value]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for value in values {
+        value.encode(&mut payload);
+    }
+    let subpkt_len = payload.len();
+    while !payload.len().is_multiple_of(4) {
+        payload.push(0);
+    }
+
+    let mut out = vec![0; HEADER_LEN];
+    out[4..6].copy_from_slice(&com_id.to_be_bytes());
+    out[16..20].copy_from_slice(&((payload.len() + PACKET_HEADER_LEN + SUBPACKET_HEADER_LEN) as u32).to_be_bytes());
+    out[20..24].copy_from_slice(&tsn.to_be_bytes());
+    out[24..28].copy_from_slice(&hsn.to_be_bytes());
+    out[40..44].copy_from_slice(&((payload.len() + SUBPACKET_HEADER_LEN) as u32).to_be_bytes());
+    out[52..56].copy_from_slice(&(subpkt_len as u32).to_be_bytes());
+    out.extend(payload);
+    out
+}
+
+/// Splits a framed response into ComPackets of at most `max_payload` subpacket payload bytes.
+/// OutstandingData of each one is the length of the data still to come.
+fn split(response: &[u8], max_payload: usize) -> VecDeque<Vec<u8>> {
+    let subpkt_len = u32::from_be_bytes(response[52..56].try_into().unwrap()) as usize;
+    let payload = &response[HEADER_LEN..HEADER_LEN + subpkt_len];
+    let mut parts: VecDeque<Vec<u8>> = payload.chunks(max_payload.max(1))
+        .map(|chunk| {
+            let mut part = response[..HEADER_LEN].to_vec();
+            part.extend(chunk);
+            part.resize(part.len().next_multiple_of(4), 0);
+            let len = part.len();
+            part[16..20].copy_from_slice(&((len - COM_PACKET_HEADER_LEN) as u32).to_be_bytes());
+            part[40..44].copy_from_slice(&((len - COM_PACKET_HEADER_LEN - PACKET_HEADER_LEN) as u32).to_be_bytes());
+            part[52..56].copy_from_slice(&(chunk.len() as u32).to_be_bytes());
+            part
+        })
+        .collect();
+    let mut outstanding = 0;
+    for part in parts.iter_mut().rev() {
+        part[8..12].copy_from_slice(&(outstanding as u32).to_be_bytes());
+        outstanding += part.len();
+    }
+    parts
+}
+
+impl SecureProtocol for SimulatedTper {
+    type Error = SimError;
+
+    unsafe fn secure_send(&mut self, protocol: u8, com_id: u16, data: &mut [u8]) -> Result<(), SimError> {
+        match (protocol, com_id) {
+            (1, com_id) if com_id == self.com_id() => {
+                self.handle_com_packet(data);
+                Ok(())
+            }
+            (1, com_id) => UnknownComIdSnafu { com_id }.fail(),
+            (2, com_id) if com_id == self.com_id() => {
+                // ComID management, only STACK_RESET
+                if data.get(4..8) == Some(&[0, 0, 0, 2]) {
+                    self.end_session();
+                    self.responses.clear();
+                    self.stack_resets += 1;
+                    let mut response = vec![0; 16];
+                    response[0..2].copy_from_slice(&com_id.to_be_bytes());
+                    response[4..8].copy_from_slice(&2u32.to_be_bytes());
+                    response[10..12].copy_from_slice(&4u16.to_be_bytes());
+                    self.com_id_response = Some(response);
+                }
+                Ok(())
+            }
+            // TPER_RESET
+            (2, 4) => {
+                self.end_session();
+                self.responses.clear();
+                self.host_properties.clear();
+                Ok(())
+            }
+            (2, 5) if self.block_sid_supported => {
+                self.sid_blocked = true;
+                self.block_sid_hardware_reset = data.first().is_some_and(|clear_events| clear_events & 0x01 != 0);
+                Ok(())
+            }
+            (protocol, _) => UnsupportedProtocolSnafu { protocol }.fail(),
+        }
+    }
+
+    unsafe fn secure_recv(&mut self, protocol: u8, com_id: u16, buffer: &mut [u8]) -> Result<(), SimError> {
+        buffer.fill(0);
+        match (protocol, com_id) {
+            (1, 1) => {
+                let discovery = self.level0_discovery();
+                let len = discovery.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&discovery[..len]);
+            }
+            (1, com_id) if com_id == self.com_id() => {
+                if buffer.len() < COM_PACKET_HEADER_LEN {
+                    return Ok(());
+                }
+                buffer[4..6].copy_from_slice(&com_id.to_be_bytes());
+                match self.responses.front() {
+                    Some(_) if self.delayed_polls < self.response_delay => {
+                        self.delayed_polls += 1;
+                        let outstanding = self.responses.iter().map(Vec::len).sum::<usize>() as u32;
+                        buffer[8..12].copy_from_slice(&outstanding.to_be_bytes());
+                    }
+                    Some(response) if response.len() <= buffer.len() => {
+                        buffer[..response.len()].copy_from_slice(response);
+                        self.responses.pop_front();
+                        self.delayed_polls = 0;
+                    }
+                    Some(response) => {
+                        // the host has to retry with a larger buffer
+                        let outstanding = self.responses.iter().map(Vec::len).sum::<usize>() as u32;
+                        buffer[8..12].copy_from_slice(&outstanding.to_be_bytes());
+                        buffer[12..16].copy_from_slice(&(response.len() as u32).to_be_bytes());
+                    }
+                    None => {}
+                }
+            }
+            (1, com_id) => return UnknownComIdSnafu { com_id }.fail(),
+            // GET_COMID, there is only the static one
+            (2, 0) => buffer[0..2].copy_from_slice(&self.com_id().to_be_bytes()),
+            (2, com_id) if com_id == self.com_id() => {
+                if let Some(response) = self.com_id_response.take() {
+                    buffer[..response.len()].copy_from_slice(&response);
+                }
+            }
+            (protocol, _) => return UnsupportedProtocolSnafu { protocol }.fail(),
+        }
+        Ok(())
+    }
+
+    fn reconnect_controller(&mut self) -> Result<(), SimError> {
+        self.reconnects += 1;
+        Ok(())
+    }
+
+    fn stall(&mut self, _microseconds: usize) {
+        self.stalls += 1;
+    }
+
+    fn align(&self) -> usize {
+        self.align
+    }
+
+    fn serial_num(&self) -> &[u8] {
+        &self.serial
+    }
+}
+
+/// Token tree of a received or sent method call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Name(Box<Value>, Box<Value>),
+    /// CALL, ENDOFDATA, ENDOFSESSION, ...
+    Control(u8),
+}
+
+impl Value {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Uint(v) => crate::defs::Token::write(v, out),
+            Value::Bytes(bytes) => {
+                let len = bytes.len();
+                if len < 16 {
+                    out.push(0xA0 | len as u8);
+                } else if len < 2048 {
+                    out.push(0xD0 | (len >> 8) as u8);
+                    out.push(len as u8);
+                } else {
+                    out.push(0xE2);
+                    out.extend(&(len as u32).to_be_bytes()[1..]);
+                }
+                out.extend(bytes);
+            }
+            Value::List(values) => {
+                out.push(token::STARTLIST.token);
+                for value in values {
+                    value.encode(out);
+                }
+                out.push(token::ENDLIST.token);
+            }
+            Value::Name(name, value) => {
+                out.push(token::STARTNAME.token);
+                name.encode(out);
+                value.encode(out);
+                out.push(token::ENDNAME.token);
+            }
+            Value::Control(t) => out.push(*t),
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+enum Atom {
+    Value(Value),
+    Token(u8),
+}
+
+impl<'a> Parser<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn parse_all(&mut self) -> Option<Vec<Value>> {
+        let values = self.parse_until(None)?;
+        Some(values)
+    }
+
+    fn parse_until(&mut self, end: Option<u8>) -> Option<Vec<Value>> {
+        let mut values = Vec::new();
+        loop {
+            let atom = match self.next_atom() {
+                Some(atom) => atom?,
+                None if end.is_none() => return Some(values),
+                None => return None,
+            };
+            match atom {
+                Atom::Value(v) => values.push(v),
+                Atom::Token(t) if Some(t) == end => return Some(values),
+                Atom::Token(t) if t == token::STARTLIST.token => {
+                    values.push(Value::List(self.parse_until(Some(token::ENDLIST.token))?));
+                }
+                Atom::Token(t) if t == token::STARTNAME.token => {
+                    let mut name = self.parse_until(Some(token::ENDNAME.token))?;
+                    if name.len() != 2 {
+                        return None;
+                    }
+                    let value = name.pop().unwrap();
+                    let name = name.pop().unwrap();
+                    values.push(Value::Name(Box::new(name), Box::new(value)));
+                }
+                Atom::Token(t) if t == token::EMPTYATOM.token => {}
+                Atom::Token(t) if [token::ENDLIST.token, token::ENDNAME.token].contains(&t) => return None,
+                Atom::Token(t) => values.push(Value::Control(t)),
+            }
+        }
+    }
+
+    /// `None` at the end of input, `Some(None)` on malformed input
+    fn next_atom(&mut self) -> Option<Option<Atom>> {
+        let &first = self.bytes.get(self.pos)?;
+        self.pos += 1;
+        let (is_bytes, len) = match first {
+            0x00..=0x7F => return Some(Some(Atom::Value(Value::Uint((first & 0x3F) as u64)))),
+            0x80..=0xBF => (first & 0x20 != 0, (first & 0x0F) as usize),
+            0xC0..=0xDF => {
+                let Some(&next) = self.bytes.get(self.pos) else { return Some(None) };
+                self.pos += 1;
+                (first & 0x10 != 0, ((first as usize & 0x07) << 8) | next as usize)
+            }
+            0xE0..=0xE3 => {
+                let Some(len) = self.bytes.get(self.pos..self.pos + 3) else { return Some(None) };
+                self.pos += 3;
+                (first & 0x02 != 0, (len[0] as usize) << 16 | (len[1] as usize) << 8 | len[2] as usize)
+            }
+            _ => return Some(Some(Atom::Token(first))),
+        };
+        let Some(data) = self.bytes.get(self.pos..self.pos + len) else { return Some(None) };
+        self.pos += len;
+        if is_bytes {
+            Some(Some(Atom::Value(Value::Bytes(data.to_vec()))))
+        } else if len <= 8 {
+            Some(Some(Atom::Value(Value::Uint(data.iter().fold(0, |acc, &b| acc << 8 | b as u64)))))
+        } else {
+            Some(None)
+        }
+    }
+}
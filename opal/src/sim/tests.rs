@@ -0,0 +1,1125 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::command::{OpalCommandBuilder, OpalResponse, Value};
+use crate::defs::{method, uid, LockingState, OpalError, StatusCode};
+use crate::io::{recv_info, LockingFlags, MAX_POLLS, PinIndicator, Properties, SecureDevice, Ssc, TperFlags};
+use crate::session::OpalSession;
+use crate::table::{locking, LockingRangeRow};
+use crate::{token_list, token_name, Authority, Error, OpalDrive, PasswordOrRaw, PinHash, Prf, Salt};
+
+use super::SimulatedTper;
+
+const SERIAL: &[u8] = b"SIM0000000000000001 ";
+const PIN: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+fn sim(drive: &mut OpalDrive<SimulatedTper>) -> &mut SimulatedTper {
+    drive.dev.proto()
+}
+
+fn status<E: core::fmt::Debug + core::fmt::Display + snafu::AsErrorSource>(res: crate::Result<(), E>) -> StatusCode {
+    match res {
+        Err(Error::Opal { source: OpalError::Status { code }, .. }) => code,
+        Err(e) => panic!("expected status code, got {e:?}"),
+        Ok(()) => StatusCode::SUCCESS,
+    }
+}
+
+/// a wrong password, as opposed to NOT_AUTHORIZED of a method called after authenticating
+fn authentication_failed<E: core::fmt::Debug + core::fmt::Display + snafu::AsErrorSource>(res: crate::Result<(), E>) -> bool {
+    matches!(res, Err(Error::Authentication { .. }))
+}
+
+#[test]
+fn level0_discovery() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    let info = recv_info(&mut tper).unwrap();
+    let opal_v2 = info.opal_v2.unwrap();
+    assert_eq!(opal_v2.base_com_id, super::BASE_COM_ID);
+    assert_eq!((opal_v2.num_locking_admins, opal_v2.num_locking_users), (4, 9));
+    assert_eq!(opal_v2.initial_pin, PinIndicator::MSID);
+    assert!(info.enterprise.is_none() && info.pyrite_v2.is_none() && info.block_sid.is_none());
+    assert!(info.tper.unwrap().contains(TperFlags::SYNC_SUPPORTED | TperFlags::COMID_MGMT_SUPPORTED));
+    let geometry = info.geometry.unwrap();
+    assert!(geometry.align);
+    assert_eq!((geometry.logical_block_size, geometry.alignment_granularity), (512, 8));
+    assert_eq!(info.datastore.unwrap().max_size, 0x0010_0000);
+    let locking = info.locking.unwrap();
+    assert!(locking.contains(LockingFlags::LOCKED | LockingFlags::LOCKING_ENABLED | LockingFlags::MBR_ENABLED));
+    assert!(!locking.contains(LockingFlags::MBR_DONE));
+}
+
+#[test]
+fn factory_drive_is_unlocked_and_rejects_locking_sp() {
+    let mut drive = OpalDrive::new(SimulatedTper::new(SERIAL)).unwrap();
+    assert!(!drive.was_locked());
+    let res = drive.unlock(PasswordOrRaw::Raw(PIN));
+    assert_eq!(status(res), StatusCode::INVALID_PARAMETER);
+}
+
+#[test]
+fn unlock_raw() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    assert!(drive.was_locked());
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+
+    let sim = sim(&mut drive);
+    assert!(!sim.range(0).is_locked());
+    assert!(sim.mbr().done);
+    assert!(!sim.has_open_session());
+    assert_eq!(sim.reconnects(), 1);
+    assert!(!drive.is_locked().unwrap());
+}
+
+#[test]
+fn unlock_password_uses_sedutil_hash() {
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(b"hunter2", SERIAL, 75000, &mut hash).unwrap();
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, &hash)).unwrap();
+    drive.unlock(PasswordOrRaw::Password(b"hunter2")).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked());
+}
+
+#[test]
+fn wrong_password_counts_tries_until_lockout() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    sim(&mut drive).set_try_limit(2);
+    let wrong = [0u8; 32];
+
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(&wrong))));
+    assert_eq!(sim(&mut drive).admin_tries(1), 1);
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(&wrong))));
+    // locked out, even the correct password is refused now
+    assert_eq!(status(drive.unlock(PasswordOrRaw::Raw(PIN))), StatusCode::AUTHORITY_LOCKED_OUT);
+    assert!(sim(&mut drive).range(0).is_locked());
+
+    sim(&mut drive).power_cycle();
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert_eq!(sim(&mut drive).admin_tries(1), 0);
+}
+
+#[test]
+fn remaining_tries() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    // only admins may read C_PIN by default
+    assert_eq!(drive.remaining_tries(Authority::Admin(1)).unwrap(), None);
+
+    sim(&mut drive).set_public_tries(true);
+    sim(&mut drive).set_try_limit(3);
+    assert_eq!(drive.remaining_tries(Authority::Admin(1)).unwrap(), Some(3));
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(&[0; 32]))));
+    assert_eq!(drive.remaining_tries(Authority::Admin(1)).unwrap(), Some(2));
+    assert_eq!(drive.remaining_tries(Authority::User(1)).unwrap(), Some(3));
+
+    sim(&mut drive).set_try_limit(0);
+    assert_eq!(drive.remaining_tries(Authority::Admin(1)).unwrap(), None);
+}
+
+#[test]
+fn successful_login_resets_tries() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(&[0; 32]))));
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert_eq!(sim(&mut drive).admin_tries(1), 0);
+}
+
+#[test]
+fn power_cycle_relocks() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    sim(&mut drive).power_cycle();
+    assert!(sim(&mut drive).range(0).is_locked());
+    assert!(!sim(&mut drive).mbr().done);
+}
+
+#[test]
+fn session_sets_non_global_range() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.range_mut(3).read_lock_enabled = true;
+    tper.range_mut(3).read_locked = true;
+    let mut dev = SecureDevice::new(tper).unwrap();
+
+    let mut session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    session.set_locking_range(3, LockingState::ReadWrite).unwrap();
+    session.set_locking_range(0, LockingState::ReadOnly).unwrap();
+    drop(session);
+
+    let sim = dev.proto();
+    assert!(!sim.range(3).is_locked());
+    assert!(!sim.range(0).read_locked);
+    assert!(sim.range(0).write_locked);
+}
+
+#[test]
+fn block_sid() {
+    let mut drive = OpalDrive::new(SimulatedTper::new(SERIAL)).unwrap();
+    assert!(matches!(drive.block_sid(false), Err(Error::Unsupported)));
+
+    let mut tper = SimulatedTper::new(SERIAL);
+    tper.support_block_sid();
+    let mut drive = OpalDrive::new(tper).unwrap();
+    let info = drive.info().block_sid.clone().unwrap();
+    assert!(!info.sid_value_changed && !info.sid_blocked);
+
+    drive.block_sid(true).unwrap();
+    let info = recv_info(sim(&mut drive)).unwrap().block_sid.unwrap();
+    assert!(info.sid_blocked && info.hardware_reset);
+    // the OS can't take ownership anymore, not even with the right password
+    assert!(authentication_failed(drive.take_ownership(PasswordOrRaw::Raw(PIN))));
+
+    sim(&mut drive).power_cycle();
+    drive.take_ownership(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(recv_info(sim(&mut drive)).unwrap().block_sid.unwrap().sid_value_changed);
+}
+
+#[test]
+fn tper_random() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let a = drive.random(70).unwrap();
+    let b = drive.random(70).unwrap();
+    assert_eq!(a.len(), 70);
+    assert_ne!(a, b);
+
+    // at most 32 bytes per call
+    let mut session = OpalSession::start(&mut drive.dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None).unwrap();
+    let command = OpalCommandBuilder::new(uid::OPAL_THISSP, method::RANDOM)
+        .payload(token_list![33u64])
+        .build();
+    assert_eq!(status(unsafe { session.send_raw_command(command) }.map(drop)), StatusCode::INVALID_PARAMETER);
+    drop(session);
+
+    let mut drive = OpalDrive::new(SimulatedTper::enterprise(SERIAL, PIN)).unwrap();
+    assert_eq!(drive.random(16).unwrap().len(), 16);
+}
+
+#[test]
+fn transaction_commits() {
+    let mut dev = SecureDevice::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let mut session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    session.transaction(|session| {
+        session.set_locking_range(0, LockingState::ReadWrite)?;
+        session.set_mbr_done(true)
+    }).unwrap();
+    drop(session);
+
+    let sim = dev.proto();
+    assert!(!sim.range(0).is_locked() && sim.mbr().done);
+}
+
+#[test]
+fn transaction_aborts() {
+    let mut dev = SecureDevice::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let mut session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    session.begin_transaction().unwrap();
+    // no nested transactions
+    assert_eq!(status(session.begin_transaction()), StatusCode::TRANSACTION_FAILURE);
+    session.set_locking_range(0, LockingState::ReadWrite).unwrap();
+    session.abort_transaction().unwrap();
+    assert_eq!(session.locking_range_state(0).unwrap(), LockingState::Locked);
+
+    // a failing method call aborts everything before it
+    let res = session.transaction(|session| {
+        session.set_locking_range(0, LockingState::ReadWrite)?;
+        session.set_pin(uid::OPAL_C_PIN_SID, PIN)
+    });
+    assert_eq!(status(res), StatusCode::NOT_AUTHORIZED);
+    assert_eq!(status(session.commit_transaction()), StatusCode::TRANSACTION_FAILURE);
+
+    // closing the session aborts the open transaction
+    session.begin_transaction().unwrap();
+    session.set_mbr_done(true).unwrap();
+    drop(session);
+    let sim = dev.proto();
+    assert!(sim.range(0).is_locked() && !sim.mbr().done);
+}
+
+#[test]
+fn only_one_session_at_a_time() {
+    let mut dev = SecureDevice::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    core::mem::forget(session);
+
+    // the first StartSession fails with SP_BUSY, resetting the ComID stack aborts the stale session
+    let session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    drop(session);
+    assert_eq!(dev.proto().stack_resets(), 1);
+}
+
+#[test]
+fn malformed_start_session_does_not_reset_the_stack() {
+    let mut dev = SecureDevice::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    // no method status: the closing ENDLIST is gone
+    dev.proto().corrupt_responses(|r| {
+        let end = 56 + u32::from_be_bytes(r[52..56].try_into().unwrap()) as usize;
+        r[end - 1] = 0x00;
+    });
+    let res = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).map(drop);
+    assert!(matches!(res, Err(Error::Opal { source: OpalError::NoMethodStatus, .. })));
+    assert_eq!(dev.proto().stack_resets(), 0);
+}
+
+#[test]
+fn com_id_management() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    assert_eq!(drive.dev.get_comid().unwrap(), super::BASE_COM_ID);
+
+    // a transaction left open is aborted with the session
+    let mut session = OpalSession::start(&mut drive.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    session.begin_transaction().unwrap();
+    session.set_mbr_done(true).unwrap();
+    core::mem::forget(session);
+    drive.stack_reset().unwrap();
+    assert!(!sim(&mut drive).has_open_session() && !sim(&mut drive).mbr().done);
+
+    let session = OpalSession::start(&mut drive.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    core::mem::forget(session);
+    let properties = drive.properties();
+    drive.dev.tper_reset().unwrap();
+    assert!(!sim(&mut drive).has_open_session());
+    assert_eq!(drive.properties(), Properties::MINIMUM);
+    assert_eq!(sim(&mut drive).host_property("MaxComPacketSize"), None);
+    // the drive exchanges them again
+    drive.tper_reset().unwrap();
+    assert_eq!(drive.properties(), properties);
+    assert!(sim(&mut drive).host_property("MaxComPacketSize").is_some());
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert_eq!(sim(&mut drive).stack_resets(), 1);
+}
+
+#[test]
+fn anybody_reads_msid() {
+    let mut dev = SecureDevice::new(SimulatedTper::new(SERIAL)).unwrap();
+    let mut session = OpalSession::start(&mut dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None).unwrap();
+    let command = OpalCommandBuilder::new(uid::OPAL_C_PIN_MSID, method::GET)
+        .payload(token_list![token_list![token_name!(3u64, 3u64), token_name!(4u64, 3u64)]])
+        .build();
+    let response = unsafe { session.send_raw_command(command) }.unwrap();
+    drop(session);
+
+    // [ [ PIN = msid ] ]
+    assert_eq!(response.tokens[..4], [vec![0xF0], vec![0xF0], vec![0xF2], vec![0x03]]);
+    assert!(response.tokens[4].ends_with(dev.proto().msid()));
+}
+
+#[test]
+fn opal_authenticate_within_session() {
+    let mut dev = SecureDevice::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let mut session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ANYBODY, None).unwrap();
+    assert_eq!(status(session.set_locking_range(0, LockingState::ReadWrite)), StatusCode::NOT_AUTHORIZED);
+    assert!(authentication_failed(session.authenticate(uid::OPAL_ADMIN1, &[0; 32])));
+    session.authenticate(uid::OPAL_ADMIN1, PIN).unwrap();
+    assert_eq!(session.locking_range_state(0).unwrap(), LockingState::Locked);
+    session.set_locking_range(0, LockingState::ReadOnly).unwrap();
+    assert_eq!(session.locking_range_state(0).unwrap(), LockingState::ReadOnly);
+}
+
+#[test]
+fn opal_v1() {
+    let info = recv_info(&mut SimulatedTper::opal_v1(SERIAL, PIN)).unwrap();
+    assert!(info.opal_v2.is_none());
+    let opal_v1 = info.opal_v1.unwrap();
+    assert_eq!(opal_v1.base_com_id, super::BASE_COM_ID);
+    assert_eq!((opal_v1.num_locking_admins, opal_v1.num_locking_users), (0, 0));
+
+    let mut drive = OpalDrive::new(SimulatedTper::opal_v1(SERIAL, PIN)).unwrap();
+    assert!(drive.was_locked());
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked() && sim(&mut drive).mbr().done);
+
+    // authentication only in StartSession
+    let mut session = OpalSession::start(&mut drive.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ANYBODY, None).unwrap();
+    assert!(matches!(session.authenticate(uid::OPAL_ADMIN1, PIN), Err(Error::Unsupported)));
+    drop(session);
+
+    drive.revert_tper(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(recv_info(sim(&mut drive)).unwrap().opal_v1.is_some());
+}
+
+#[test]
+fn pyrite() {
+    let info = recv_info(&mut SimulatedTper::pyrite(SERIAL, PIN)).unwrap();
+    assert!(info.opal_v2.is_none() && info.pyrite_v2.is_some());
+    assert!(!info.locking.unwrap().contains(LockingFlags::MEDIA_ENCRYPTION));
+
+    let mut drive = OpalDrive::new(SimulatedTper::pyrite(SERIAL, PIN)).unwrap();
+    assert_eq!(drive.ssc(), Ssc::PyriteV2);
+    assert!(!drive.media_encryption());
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked() && sim(&mut drive).mbr().done);
+
+    // no keys to erase and only the global range
+    let res = drive.erase_ranges(PasswordOrRaw::Raw(PIN), Authority::default(), &[0]);
+    assert!(matches!(res, Err(Error::Unsupported)));
+    let res = drive.unlock_ranges(PasswordOrRaw::Raw(PIN), Authority::default(), &[1]);
+    assert!(res.is_err());
+}
+
+#[test]
+fn ruby() {
+    let info = recv_info(&mut SimulatedTper::ruby(SERIAL, PIN)).unwrap();
+    assert!(info.opal_v2.is_none() && info.ruby.is_some());
+    assert!(info.locking.unwrap().contains(LockingFlags::MEDIA_ENCRYPTION | LockingFlags::MBR_SHADOWING_NOT_SUPPORTED));
+
+    let mut drive = OpalDrive::new(SimulatedTper::ruby(SERIAL, PIN)).unwrap();
+    assert_eq!(drive.ssc(), Ssc::Ruby);
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked() && !sim(&mut drive).mbr().done);
+    drive.erase_ranges(PasswordOrRaw::Raw(PIN), Authority::default(), &[1]).unwrap();
+}
+
+#[test]
+fn enterprise_level0_discovery() {
+    let mut tper = SimulatedTper::enterprise(SERIAL, PIN);
+    let info = recv_info(&mut tper).unwrap();
+    assert_eq!(info.enterprise.unwrap().base_com_id, super::ENTERPRISE_COM_ID);
+    assert!(info.opal_v2.is_none());
+    assert!(info.locking.unwrap().contains(LockingFlags::LOCKED | LockingFlags::MBR_SHADOWING_NOT_SUPPORTED));
+}
+
+#[test]
+fn enterprise_unlock() {
+    let mut drive = OpalDrive::new(SimulatedTper::enterprise(SERIAL, PIN)).unwrap();
+    assert!(drive.was_locked());
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+
+    let sim = sim(&mut drive);
+    assert!(!sim.range(0).is_locked());
+    assert!(!sim.has_open_session());
+    assert_eq!(sim.reconnects(), 1);
+}
+
+#[test]
+fn enterprise_wrong_password() {
+    let mut drive = OpalDrive::new(SimulatedTper::enterprise(SERIAL, PIN)).unwrap();
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(&[0; 32]))));
+    assert!(sim(&mut drive).range(0).is_locked());
+    assert!(!sim(&mut drive).has_open_session());
+}
+
+#[test]
+fn enterprise_unlock_band() {
+    let other = [0x42; 32];
+    let mut tper = SimulatedTper::enterprise(SERIAL, PIN);
+    tper.set_band_master_pin(2, &other);
+    tper.range_mut(2).read_lock_enabled = true;
+    tper.range_mut(2).read_locked = true;
+    let mut drive = OpalDrive::new(tper).unwrap();
+
+    // BandMaster0 has no access to band 2
+    let mut session = drive.start_session(uid::ENTERPRISE_LOCKINGSP, uid::band_master(0), Some(PasswordOrRaw::Raw(PIN))).unwrap();
+    assert_eq!(status(session.set_locking_range(2, LockingState::ReadWrite)), StatusCode::NOT_AUTHORIZED);
+    drop(session);
+
+    drive.unlock_ranges(PasswordOrRaw::Raw(&other), Authority::default(), &[2]).unwrap();
+    assert!(!sim(&mut drive).range(2).is_locked());
+    assert!(sim(&mut drive).range(0).is_locked());
+
+    let mut session = drive.start_session(uid::ENTERPRISE_LOCKINGSP, uid::band_master(2), Some(PasswordOrRaw::Raw(&other))).unwrap();
+    assert_eq!(session.locking_range_state(2).unwrap(), LockingState::ReadWrite);
+}
+
+#[test]
+fn unlock_selected_ranges() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    for n in 1..=3 {
+        tper.range_mut(n).read_lock_enabled = true;
+        tper.range_mut(n).read_locked = true;
+    }
+    let mut drive = OpalDrive::new(tper).unwrap();
+    drive.unlock_ranges(PasswordOrRaw::Raw(PIN), Authority::default(), &[1, 3]).unwrap();
+
+    let sim = sim(&mut drive);
+    assert!(!sim.range(1).is_locked() && !sim.range(3).is_locked());
+    assert!(sim.range(0).is_locked() && sim.range(2).is_locked());
+    // the shadow MBR covers the global range, which is still locked
+    assert!(!sim.mbr().done);
+    assert_eq!(sim.reconnects(), 1);
+}
+
+#[test]
+fn user_unlocks_granted_ranges() {
+    let user_pin = [0x55; 32];
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.enable_user(2);
+    tper.set_user_pin(2, &user_pin);
+    tper.grant_range(0, 2);
+    tper.grant_range(1, 2);
+    tper.range_mut(1).read_lock_enabled = true;
+    tper.range_mut(1).read_locked = true;
+    tper.range_mut(2).read_lock_enabled = true;
+    tper.range_mut(2).read_locked = true;
+    let mut drive = OpalDrive::new(tper).unwrap();
+
+    // the admin password doesn't work for a user
+    let res = drive.unlock_ranges(PasswordOrRaw::Raw(PIN), Authority::User(2), &[1]);
+    assert!(authentication_failed(res));
+    // neither does a range that wasn't granted
+    let res = drive.unlock_ranges(PasswordOrRaw::Raw(&user_pin), Authority::User(2), &[2]);
+    assert_eq!(status(res), StatusCode::NOT_AUTHORIZED);
+
+    // MBRControl isn't granted, unlocking the global range still succeeds
+    drive.unlock_ranges(PasswordOrRaw::Raw(&user_pin), Authority::User(2), &[0, 1]).unwrap();
+    let sim = sim(&mut drive);
+    assert!(!sim.range(0).is_locked() && !sim.range(1).is_locked());
+    assert!(sim.range(2).is_locked());
+    assert!(!sim.mbr().done);
+}
+
+#[test]
+fn disabled_user_cannot_authenticate() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.set_user_pin(1, PIN);
+    tper.grant_range(0, 1);
+    let mut drive = OpalDrive::new(tper).unwrap();
+    let res = drive.unlock_ranges(PasswordOrRaw::Raw(PIN), Authority::User(1), &[0]);
+    assert!(authentication_failed(res));
+    assert!(sim(&mut drive).range(0).is_locked());
+}
+
+#[test]
+fn response_value_tree() {
+    let mut long = vec![0xE2, 0x00, 0x08, 0x00];
+    long.extend([0xAB; 0x800]);
+    let mut medium = vec![0xD0, 0x11];
+    medium.extend([0x42; 0x11]);
+    let tokens = vec![
+        vec![0xF0],
+        vec![0xF2], vec![0x03], medium, vec![0xF3],
+        vec![0x7F],
+        vec![0x92, 0xFF, 0x00],
+        vec![0x89, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+        long,
+        vec![0xF1],
+        vec![0xF9],
+        vec![0xF0], vec![0x00], vec![0x00], vec![0x00], vec![0xF1],
+    ];
+    let response = OpalResponse { header: Default::default(), tokens };
+
+    let values = response.values().unwrap();
+    assert_eq!(values[1], Value::Control(0xF9));
+    let results = response.results().unwrap();
+    assert_eq!(results[0], Value::Name(Box::new(Value::Uint(3)), Box::new(Value::Bytes(vec![0x42; 0x11]))));
+    assert_eq!(Value::List(results.clone()).get(&Value::Uint(3)).and_then(Value::as_bytes), Some(&[0x42; 0x11][..]));
+    assert_eq!(results[1], Value::Int(-1));
+    assert_eq!(results[2], Value::Int(-256));
+    assert_eq!(results[3], Value::Uint(u64::MAX));
+    assert_eq!(results[4].as_bytes().unwrap().len(), 0x800);
+
+    let truncated = OpalResponse { header: Default::default(), tokens: vec![vec![0xF0], vec![0xF2], vec![0x03]] };
+    assert!(truncated.values().is_none());
+}
+
+#[test]
+fn get_uint_excludes_atom_header() {
+    // the header byte of short atoms used to be decoded as the most significant byte
+    let tokens = vec![vec![0x05], vec![0x82, 0x12, 0x34], vec![0x84, 0x00, 0x00, 0x10, 0x00], vec![0xA2, 0x12, 0x34]];
+    let response = OpalResponse { header: Default::default(), tokens };
+    assert_eq!(response.get_uint(0), Some(5));
+    assert_eq!(response.get_uint(1), Some(0x1234));
+    assert_eq!(response.get_uint(2), Some(0x1000));
+    assert_eq!(response.get_uint(3), None);
+    assert_eq!(response.get_uint(4), None);
+}
+
+#[test]
+fn value_encoding_roundtrip() {
+    use crate::defs::Token;
+    for value in [Value::Uint(5), Value::Uint(0x1234_5678), Value::Int(-3), Value::Int(-200), Value::Int(i64::MIN), Value::Int(300)] {
+        let mut encoded = Vec::new();
+        value.write(&mut encoded);
+        let response = OpalResponse { header: Default::default(), tokens: vec![encoded] };
+        assert_eq!(response.values().unwrap(), [value]);
+    }
+}
+
+#[test]
+fn typed_locking_range_row() {
+    let mut dev = SecureDevice::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let mut session = OpalSession::start(&mut dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    let global = session.locking_range(0).unwrap();
+    assert!(global.is_locked() && global.read_lock_enabled && global.write_lock_enabled);
+
+    let row = LockingRangeRow {
+        range_start: 0x1000,
+        range_length: 0x8000,
+        read_lock_enabled: true,
+        write_lock_enabled: true,
+        read_locked: false,
+        write_locked: true,
+    };
+    session.set_locking_range_row(2, &row).unwrap();
+    assert_eq!(session.locking_range(2).unwrap(), row);
+    assert_eq!(session.locking_range_state(2).unwrap(), LockingState::ReadOnly);
+
+    let mbr = session.get(uid::OPAL_MBRCONTROL, crate::table::mbr_control::ENABLE, crate::table::mbr_control::DONE).unwrap();
+    assert_eq!(mbr.bool(crate::table::mbr_control::ENABLE), Some(true));
+    // start and length of the global range are fixed
+    let res = session.set(uid::locking_range(0), &[(locking::RANGE_START, Value::Uint(1))]);
+    assert_eq!(status(res), StatusCode::INVALID_PARAMETER);
+}
+
+#[test]
+fn enterprise_typed_band_row() {
+    let mut dev = SecureDevice::new(SimulatedTper::enterprise(SERIAL, PIN)).unwrap();
+    let mut session = OpalSession::start(&mut dev, uid::ENTERPRISE_LOCKINGSP, uid::band_master(0), Some(PIN)).unwrap();
+    let mut row = session.locking_range(0).unwrap();
+    assert!(row.is_locked());
+    row.read_locked = false;
+    row.write_locked = false;
+    session.set_locking_range_row(0, &row).unwrap();
+    assert_eq!(session.locking_range_state(0).unwrap(), LockingState::ReadWrite);
+}
+
+fn malformed_error(corrupt: fn(&mut Vec<u8>)) -> OpalError {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    sim(&mut drive).corrupt_responses(corrupt);
+    match drive.unlock(PasswordOrRaw::Raw(PIN)) {
+        Err(Error::Opal { source, .. }) => source,
+        res => panic!("expected a malformed response error, got {res:?}"),
+    }
+}
+
+#[test]
+fn malformed_responses_are_errors() {
+    // lengths larger than the data
+    assert!(matches!(malformed_error(|r| r[16..20].copy_from_slice(&u32::MAX.to_be_bytes())), OpalError::MalformedComPacket));
+    assert!(matches!(malformed_error(|r| r[40..44].copy_from_slice(&0x1000u32.to_be_bytes())), OpalError::MalformedComPacket));
+    assert!(matches!(malformed_error(|r| r[52..56].copy_from_slice(&u32::MAX.to_be_bytes())), OpalError::MalformedComPacket));
+    // a long atom claiming more data than the subpacket has
+    assert!(matches!(malformed_error(|r| {
+        r[56] = 0xE0;
+        r[57] = 0xFF;
+    }), OpalError::MalformedResponse));
+    // a medium atom header as the very last byte of the subpacket
+    assert!(matches!(malformed_error(|r| {
+        let end = 56 + u32::from_be_bytes(r[52..56].try_into().unwrap()) as usize;
+        r[end - 1] = 0xC0;
+    }), OpalError::MalformedResponse));
+    // lists nested far deeper than any response needs, parsing them mustn't overflow the stack
+    assert!(matches!(malformed_error(|r| {
+        r.truncate(56);
+        r.resize(56 + 2002, 0xF0);
+        // EOD [ SUCCESS 0 0 ], so it gets past the method status check
+        r.extend([0xF9, 0xF0, 0x00, 0x00, 0x00, 0xF1]);
+        let len = r.len() as u32 - 56;
+        r[16..20].copy_from_slice(&(24 + 12 + len).to_be_bytes());
+        r[40..44].copy_from_slice(&(12 + len).to_be_bytes());
+        r[52..56].copy_from_slice(&len.to_be_bytes());
+    }), OpalError::MalformedResponse));
+    // well-formed apart from the depth
+    let nested = |depth: usize| {
+        let mut tokens = vec![0xF0; depth];
+        tokens.resize(2 * depth, 0xF1);
+        let len = tokens.len() as u32;
+        let mut response = vec![0; 56];
+        response[16..20].copy_from_slice(&(24 + 12 + len).to_be_bytes());
+        response[40..44].copy_from_slice(&(12 + len).to_be_bytes());
+        response[52..56].copy_from_slice(&len.to_be_bytes());
+        response.extend(tokens);
+        OpalResponse::parse(&response).unwrap().values()
+    };
+    assert!(nested(8).is_some());
+    assert!(nested(2000).is_none());
+}
+
+#[test]
+fn multi_part_responses_are_joined() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    sim(&mut drive).split_responses(5);
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked());
+}
+
+#[test]
+fn responses_not_ready_are_polled() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    sim(&mut drive).delay_responses(3);
+    sim(&mut drive).split_responses(64);
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked());
+    assert!(sim(&mut drive).stalls() > 3);
+
+    // a TPer that never finishes
+    let stalls = sim(&mut drive).stalls();
+    sim(&mut drive).delay_responses(usize::MAX);
+    let res = drive.unlock(PasswordOrRaw::Raw(PIN));
+    assert!(matches!(res, Err(Error::Opal { source: OpalError::NoResponse, .. })));
+    // StartSession and the EndSession of the dropped session both give up
+    assert_eq!(sim(&mut drive).stalls() - stalls, 2 * (MAX_POLLS - 1));
+}
+
+fn read_msid(dev: &mut SecureDevice<SimulatedTper>) -> Vec<u8> {
+    let mut session = OpalSession::start(dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None).unwrap();
+    let pin = crate::table::Column::new(3, "PIN");
+    let row = session.get(uid::OPAL_C_PIN_MSID, pin, pin).unwrap();
+    row.get(pin).and_then(Value::as_bytes).unwrap().to_vec()
+}
+
+#[test]
+fn large_response_grows_the_buffer() {
+    let msid: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let mut tper = SimulatedTper::new(SERIAL);
+    tper.set_msid(&msid);
+    let mut dev = SecureDevice::new(tper).unwrap();
+    assert_eq!(read_msid(&mut dev), msid);
+
+    // both at once: the first part alone doesn't fit either
+    dev.proto().split_responses(3000);
+    assert_eq!(read_msid(&mut dev), msid);
+}
+
+#[test]
+fn properties_are_negotiated() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let properties = drive.properties();
+    assert_eq!(properties, Properties {
+        max_com_packet_size: 0x4000,
+        max_packet_size: 0x4000 - 20,
+        max_ind_token_size: 0x4000 - 56,
+        max_packets: 1,
+        max_subpackets: 1,
+        max_methods: 1,
+    });
+    assert_eq!(properties.max_payload(), 0x4000 - 56);
+    assert!(properties.max_set_bytes() < properties.max_payload());
+    assert_eq!(sim(&mut drive).host_property("MaxComPacketSize"), Some(Properties::HOST.max_com_packet_size as u64));
+    // Properties isn't a session
+    assert!(!sim(&mut drive).has_open_session());
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+}
+
+#[test]
+fn commands_larger_than_negotiated_are_refused() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.set_max_com_packet_size(1024);
+    let mut drive = OpalDrive::new(tper).unwrap();
+    assert_eq!(drive.properties().max_com_packet_size, 1024);
+
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::OPAL_ANYBODY, None).unwrap();
+    let res = session.authenticate(uid::OPAL_ADMIN1, &[0; 1000]);
+    assert!(matches!(res, Err(Error::Opal { source: OpalError::CommandTooLarge, .. })));
+    // the session is still usable
+    session.authenticate(uid::OPAL_ADMIN1, PIN).unwrap();
+    session.set_locking_range(0, LockingState::ReadWrite).unwrap();
+}
+
+#[test]
+fn change_admin_password() {
+    let new = [0x77; 32];
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let res = drive.change_password(Authority::Admin(1), PasswordOrRaw::Raw(&new), PasswordOrRaw::Raw(&new));
+    assert!(authentication_failed(res));
+
+    drive.change_password(Authority::Admin(1), PasswordOrRaw::Raw(PIN), PasswordOrRaw::Raw(&new)).unwrap();
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(PIN))));
+    drive.unlock(PasswordOrRaw::Raw(&new)).unwrap();
+}
+
+#[test]
+fn change_password_hashes_like_unlock() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    drive.change_password(Authority::Sid, PasswordOrRaw::Raw(PIN), PasswordOrRaw::Password(b"sid")).unwrap();
+    drive.change_password(Authority::Admin(1), PasswordOrRaw::Raw(PIN), PasswordOrRaw::Password(b"admin")).unwrap();
+    drive.change_password(Authority::Sid, PasswordOrRaw::Password(b"sid"), PasswordOrRaw::Password(b"sid2")).unwrap();
+    drive.unlock(PasswordOrRaw::Password(b"admin")).unwrap();
+}
+
+#[test]
+fn user_changes_own_password() {
+    let new = [0x66; 32];
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.enable_user(1);
+    tper.set_user_pin(1, PIN);
+    tper.grant_range(0, 1);
+    let mut drive = OpalDrive::new(tper).unwrap();
+
+    // but not somebody else's
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::user(1), Some(PasswordOrRaw::Raw(PIN))).unwrap();
+    assert_eq!(status(session.set_pin(uid::c_pin_admin(1), &new)), StatusCode::NOT_AUTHORIZED);
+    drop(session);
+
+    drive.change_password(Authority::User(1), PasswordOrRaw::Raw(PIN), PasswordOrRaw::Raw(&new)).unwrap();
+    drive.unlock_ranges(PasswordOrRaw::Raw(&new), Authority::User(1), &[0]).unwrap();
+    // Admin1 is unaffected
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+}
+
+#[test]
+fn initial_setup_with_plain_passwords() {
+    // PINs that aren't 32 bytes long are passed on as they are
+    let mut drive = OpalDrive::new(SimulatedTper::new(SERIAL)).unwrap();
+    drive.initial_setup(PasswordOrRaw::Hashed(b"sid", PinHash::Plain), PasswordOrRaw::Hashed(b"admin1", PinHash::Plain)).unwrap();
+    sim(&mut drive).power_cycle();
+    drive.unlock(PasswordOrRaw::Hashed(b"admin1", PinHash::Plain)).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked());
+}
+
+#[test]
+fn provisioning_lifecycle() {
+    let sid = [0x51; 32];
+    let mut drive = OpalDrive::new(SimulatedTper::new(SERIAL)).unwrap();
+    let msid = drive.msid().unwrap();
+    assert_eq!(msid, sim(&mut drive).msid());
+
+    drive.initial_setup(PasswordOrRaw::Raw(&sid), PasswordOrRaw::Raw(PIN)).unwrap();
+    let tper = sim(&mut drive);
+    assert!(tper.locking_sp_active());
+    assert!(tper.range(0).read_lock_enabled && tper.range(0).write_lock_enabled && tper.range(0).lock_on_reset);
+    assert!(!tper.range(0).is_locked());
+    assert!(!tper.has_open_session());
+
+    // MSID doesn't work anymore, the new passwords do
+    assert!(authentication_failed(drive.take_ownership(PasswordOrRaw::Raw(&sid))));
+    sim(&mut drive).power_cycle();
+    assert!(drive.is_locked().unwrap());
+    assert!(authentication_failed(drive.unlock(PasswordOrRaw::Raw(&sid))));
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    drive.change_password(Authority::Sid, PasswordOrRaw::Raw(&sid), PasswordOrRaw::Raw(PIN)).unwrap();
+}
+
+#[test]
+fn activate_copies_sid_to_admin1() {
+    let sid = [0x51; 32];
+    let mut drive = OpalDrive::new(SimulatedTper::new(SERIAL)).unwrap();
+    assert!(authentication_failed(drive.activate_locking_sp(PasswordOrRaw::Raw(&sid))));
+    drive.take_ownership(PasswordOrRaw::Raw(&sid)).unwrap();
+    drive.activate_locking_sp(PasswordOrRaw::Raw(&sid)).unwrap();
+    // activating twice is harmless
+    drive.activate_locking_sp(PasswordOrRaw::Raw(&sid)).unwrap();
+    drive.enable_global_locking(PasswordOrRaw::Raw(&sid)).unwrap();
+    assert!(sim(&mut drive).range(0).lock_on_reset);
+}
+
+#[test]
+fn single_user_mode() {
+    let sid = [0x51; 32];
+    let user_pin = [0x75; 32];
+    let mut tper = SimulatedTper::new(SERIAL);
+    tper.support_single_user_mode();
+    let mut drive = OpalDrive::new(tper).unwrap();
+    let info = drive.single_user_mode().unwrap().unwrap();
+    assert_eq!(info.num_locking_objects, 9);
+    assert!(!info.any && !info.all);
+
+    drive.take_ownership(PasswordOrRaw::Raw(&sid)).unwrap();
+    drive.activate_locking_sp_single_user(PasswordOrRaw::Raw(&sid), &[1, 2], true).unwrap();
+    let info = drive.single_user_mode().unwrap().unwrap();
+    assert!(info.any && !info.all && info.policy);
+    assert!(sim(&mut drive).range(1).single_user && !sim(&mut drive).range(3).single_user);
+
+    // only User2 sets its password and locks range 1, Admin1 keeps the other ranges
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PasswordOrRaw::Raw(&sid))).unwrap();
+    assert_eq!(status(session.set_pin(uid::c_pin_user(2), &user_pin)), StatusCode::NOT_AUTHORIZED);
+    drop(session);
+    drive.set_single_user_password(1, PasswordOrRaw::Raw(&user_pin)).unwrap();
+    let res = drive.lock_ranges(PasswordOrRaw::Raw(&sid), Authority::default(), &[1]);
+    assert_eq!(status(res), StatusCode::NOT_AUTHORIZED);
+    drive.lock_ranges(PasswordOrRaw::Raw(&user_pin), Authority::single_user(1), &[1]).unwrap();
+    drive.lock_ranges(PasswordOrRaw::Raw(&sid), Authority::default(), &[3]).unwrap();
+    assert!(sim(&mut drive).range(1).read_locked && sim(&mut drive).range(3).read_locked);
+
+    drive.unlock_single_user_range(PasswordOrRaw::Raw(&user_pin), 1).unwrap();
+    assert!(!sim(&mut drive).range(1).read_locked && !sim(&mut drive).range(1).write_locked);
+    let res = drive.unlock_single_user_range(PasswordOrRaw::Raw(&user_pin), 2);
+    assert!(authentication_failed(res));
+
+    // with RangeStartRangeLengthPolicy 1 only the admins set the bounds
+    let start = [(locking::RANGE_START, Value::Uint(64))];
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::user(2), Some(PasswordOrRaw::Raw(&user_pin))).unwrap();
+    assert_eq!(status(session.set(uid::locking_range(1), &start)), StatusCode::NOT_AUTHORIZED);
+    drop(session);
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PasswordOrRaw::Raw(&sid))).unwrap();
+    session.set(uid::locking_range(1), &start).unwrap();
+    drop(session);
+
+    // with policy 0 the user may set them too
+    let mut tper = SimulatedTper::new(SERIAL);
+    tper.support_single_user_mode();
+    drive = OpalDrive::new(tper).unwrap();
+    drive.take_ownership(PasswordOrRaw::Raw(&sid)).unwrap();
+    drive.activate_locking_sp_single_user(PasswordOrRaw::Raw(&sid), &[1], false).unwrap();
+    assert!(!drive.single_user_mode().unwrap().unwrap().policy);
+    // activating twice is harmless
+    drive.activate_locking_sp_single_user(PasswordOrRaw::Raw(&sid), &[1], false).unwrap();
+    drive.set_single_user_password(1, PasswordOrRaw::Raw(&user_pin)).unwrap();
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::user(2), Some(PasswordOrRaw::Raw(&user_pin))).unwrap();
+    session.set(uid::locking_range(1), &start).unwrap();
+    drop(session);
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PasswordOrRaw::Raw(&sid))).unwrap();
+    session.set(uid::locking_range(1), &start).unwrap();
+    drop(session);
+
+    // without the feature
+    let mut drive = OpalDrive::new(SimulatedTper::new(SERIAL)).unwrap();
+    assert!(drive.single_user_mode().unwrap().is_none());
+    let res = drive.activate_locking_sp_single_user(PasswordOrRaw::Raw(&sid), &[1], false);
+    assert!(matches!(res, Err(Error::Unsupported)));
+}
+
+#[test]
+fn revert_tper_restores_factory_state() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    assert!(authentication_failed(drive.revert_tper(PasswordOrRaw::Raw(&[0; 32]))));
+    assert!(!sim(&mut drive).has_open_session());
+
+    drive.revert_tper(PasswordOrRaw::Raw(PIN)).unwrap();
+    let tper = sim(&mut drive);
+    assert!(!tper.locking_sp_active());
+    assert!(!tper.range(0).is_locked() && !tper.mbr().enable);
+    assert!(!tper.has_open_session());
+    assert_eq!(tper.reconnects(), 1);
+    // SID is back to MSID
+    drive.take_ownership(PasswordOrRaw::Raw(PIN)).unwrap();
+}
+
+#[test]
+fn revert_locking_sp_keeps_sid() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    drive.revert_locking_sp(PasswordOrRaw::Raw(PIN)).unwrap();
+    let tper = sim(&mut drive);
+    assert!(!tper.locking_sp_active());
+    assert!(!tper.range(0).is_locked() && !tper.has_open_session());
+    assert_eq!(status(drive.unlock(PasswordOrRaw::Raw(PIN))), StatusCode::INVALID_PARAMETER);
+
+    // Admin1 gets the SID password again once reactivated
+    drive.activate_locking_sp(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert!(authentication_failed(drive.take_ownership(PasswordOrRaw::Raw(PIN))));
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+}
+
+#[test]
+fn psid_revert() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    assert!(authentication_failed(drive.psid_revert(b"not the psid")));
+    assert!(sim(&mut drive).locking_sp_active());
+
+    // PSID can't do anything but Revert
+    let mut session = drive.start_session(uid::OPAL_ADMINSP, uid::OPAL_PSID, Some(PasswordOrRaw::Raw(super::PSID))).unwrap();
+    assert_eq!(status(session.set_pin(uid::OPAL_C_PIN_SID, PIN)), StatusCode::NOT_AUTHORIZED);
+    drop(session);
+
+    drive.psid_revert(super::PSID).unwrap();
+    let tper = sim(&mut drive);
+    assert!(!tper.locking_sp_active() && !tper.range(0).is_locked());
+    assert!(!tper.has_open_session());
+    drive.take_ownership(PasswordOrRaw::Raw(PIN)).unwrap();
+}
+
+#[test]
+fn enterprise_psid_revert() {
+    let mut drive = OpalDrive::new(SimulatedTper::enterprise(SERIAL, PIN)).unwrap();
+    drive.psid_revert(super::PSID).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked());
+    // BandMaster0 is back to MSID
+    let msid = sim(&mut drive).msid().to_vec();
+    OpalSession::start(&mut drive.dev, uid::ENTERPRISE_LOCKINGSP, uid::band_master(0), Some(&msid)).unwrap();
+}
+
+#[test]
+fn erase_regenerates_keys() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PasswordOrRaw::Raw(PIN))).unwrap();
+    assert_eq!(session.active_key(0).unwrap().bytes, 0x0000_0806_0000_0001u64.to_be_bytes());
+    assert_eq!(session.active_key(2).unwrap().bytes, 0x0000_0806_0003_0002u64.to_be_bytes());
+    drop(session);
+
+    drive.erase_ranges(PasswordOrRaw::Raw(PIN), Authority::Admin(1), &[0, 2]).unwrap();
+    let tper = sim(&mut drive);
+    assert_eq!([0, 1, 2].map(|n| tper.range(n).key_generation), [1, 0, 1]);
+    // GenKey doesn't touch the locking state
+    assert!(tper.range(0).is_locked());
+    assert!(!tper.has_open_session());
+    assert_eq!(tper.reconnects(), 1);
+}
+
+#[test]
+fn users_cannot_erase() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.enable_user(1);
+    tper.set_user_pin(1, PIN);
+    tper.grant_range(0, 1);
+    let mut drive = OpalDrive::new(tper).unwrap();
+    let res = drive.erase_ranges(PasswordOrRaw::Raw(PIN), Authority::User(1), &[0]);
+    assert_eq!(status(res), StatusCode::NOT_AUTHORIZED);
+    assert_eq!(sim(&mut drive).range(0).key_generation, 0);
+}
+
+#[test]
+fn enterprise_erase_resets_band() {
+    let mut tper = SimulatedTper::enterprise(SERIAL, PIN);
+    tper.set_band_master_pin(1, PIN);
+    *tper.range_mut(1) = tper.range(0).clone();
+    tper.range_mut(1).start = 0x1000;
+    let mut drive = OpalDrive::new(tper).unwrap();
+
+    // BandMasters can't erase, only EraseMaster
+    let mut session = drive.start_session(uid::ENTERPRISE_LOCKINGSP, uid::band_master(1), Some(PasswordOrRaw::Raw(PIN))).unwrap();
+    assert_eq!(status(session.erase_locking_range(1)), StatusCode::NOT_AUTHORIZED);
+    drop(session);
+
+    drive.erase_ranges(PasswordOrRaw::Raw(PIN), Authority::default(), &[1]).unwrap();
+    let range = sim(&mut drive).range(1).clone();
+    assert_eq!((range.start, range.key_generation), (0x1000, 1));
+    assert!(!range.read_lock_enabled && !range.is_locked());
+    let msid = sim(&mut drive).msid().to_vec();
+    OpalSession::start(&mut drive.dev, uid::ENTERPRISE_LOCKINGSP, uid::band_master(1), Some(&msid)).unwrap();
+}
+
+#[test]
+fn datastore_roundtrip_in_chunks() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.set_max_com_packet_size(2048);
+    let mut drive = OpalDrive::new(tper).unwrap();
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    assert!(data.len() > 2 * drive.properties().max_set_bytes());
+
+    drive.write_datastore(PasswordOrRaw::Raw(PIN), Authority::Admin(1), 100, &data).unwrap();
+    assert_eq!(&sim(&mut drive).datastore()[100..5100], data.as_slice());
+    assert_eq!(sim(&mut drive).datastore()[99], 0);
+    let read = drive.read_datastore(PasswordOrRaw::Raw(PIN), Authority::Admin(1), 100, data.len()).unwrap();
+    assert_eq!(read, data);
+}
+
+#[test]
+fn datastore_reads_are_sized_by_the_response_limit() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.set_max_com_packet_size(2048);
+    let mut drive = OpalDrive::new(tper).unwrap();
+    let mut properties = drive.properties();
+    assert!(properties.max_get_bytes() > properties.max_set_bytes());
+    let len = 2 * properties.max_get_bytes();
+    sim(&mut drive).datastore_mut()[..len].fill(0x42);
+
+    // two Gets whose responses are as large as allowed
+    let mut session = OpalSession::start(&mut drive.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    assert_eq!(session.read_datastore(0, len).unwrap(), vec![0x42; len]);
+    drop(session);
+    assert_eq!(sim(&mut drive).method_calls(), 2);
+
+    // any larger and they overflow
+    properties.max_com_packet_size += 4;
+    properties.max_packet_size += 4;
+    drive.dev.set_properties(properties);
+    let mut session = OpalSession::start(&mut drive.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    assert_eq!(status(session.read_datastore(0, len).map(drop)), StatusCode::RESPONSE_OVERFLOW);
+}
+
+#[test]
+fn datastore_needs_authentication() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.datastore_mut()[..4].copy_from_slice(b"key!");
+    tper.enable_user(1);
+    tper.set_user_pin(1, PIN);
+    let mut drive = OpalDrive::new(tper).unwrap();
+
+    let mut session = drive.start_session(uid::OPAL_LOCKINGSP, uid::OPAL_ANYBODY, None).unwrap();
+    assert!(matches!(session.read_datastore(0, 4), Err(Error::Opal { source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED }, .. })));
+    drop(session);
+    let res = drive.read_datastore(PasswordOrRaw::Raw(PIN), Authority::User(1), 0, 4);
+    assert!(matches!(res, Err(Error::Opal { source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED }, .. })));
+    assert_eq!(drive.read_datastore(PasswordOrRaw::Raw(PIN), Authority::Admin(1), 0, 4).unwrap(), b"key!");
+
+    // out of bounds
+    let size = drive.info().datastore.as_ref().unwrap().max_size;
+    let res = drive.read_datastore(PasswordOrRaw::Raw(PIN), Authority::Admin(1), size - 2, 4);
+    assert!(matches!(res, Err(Error::Opal { source: OpalError::Status { code: StatusCode::INVALID_PARAMETER }, .. })));
+
+    // reverting the Locking SP clears it
+    drive.revert_locking_sp(PasswordOrRaw::Raw(PIN)).unwrap();
+    assert_eq!(sim(&mut drive).datastore()[..4], [0; 4]);
+}
+
+#[test]
+fn pin_hash_schemes() {
+    // PBKDF2 test vectors for P = "password", S = "salt", c = 1
+    let sha256 = b"\x12\x0f\xb6\xcf\xfc\xf8\xb3\x2c\x43\xe7\x22\x52\x56\xc4\xf8\x37\xa8\x65\x48\xc9\x2c\xcc\x35\x48\x08\x05\x98\x7c\xb7\x0b\xe1\x7b";
+    let sha512 = b"\x86\x7f\x70\xcf\x1a\xde\x02\xcf\xf3\x75\x25\x99\xa3\xa5\x3d\xc4\xaf\x34\xc7\xa6\x69\x81\x5a\xe5\xd5\x13\x55\x4e\x1c\x8c\xf2\x52\xc0\x2d\x47\x0a\x28\x5a\x05\x01\xba\xd9\x99\xbf\xe9\x43\xc0\x8f\x05\x02\x35\xd7\xd6\x8b\x1d\xa5\x5e\x63\xf7\x3b\x60\xa5\x7f\xce";
+    let pbkdf2 = |prf, len| PinHash::Pbkdf2 { prf, iterations: 1, salt: Salt::Fixed(b"salt".to_vec()), len };
+
+    for (pin, hash) in [(&sha256[..], pbkdf2(Prf::HmacSha256, 32)), (&sha512[..], pbkdf2(Prf::HmacSha512, 64))] {
+        let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, pin)).unwrap();
+        drive.unlock(PasswordOrRaw::Hashed(b"password", hash)).unwrap();
+        assert!(!sim(&mut drive).range(0).is_locked());
+    }
+
+    // the serial is the default salt, as with sedutil
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(b"hunter2", SERIAL, 75000, &mut hash).unwrap();
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, &hash)).unwrap();
+    drive.unlock(PasswordOrRaw::Hashed(b"hunter2", PinHash::SEDUTIL)).unwrap();
+
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, b"kernel sed-opal key")).unwrap();
+    drive.unlock(PasswordOrRaw::Hashed(b"kernel sed-opal key", PinHash::Plain)).unwrap();
+
+    let empty = PinHash::Pbkdf2 { prf: Prf::HmacSha1, iterations: 1, salt: Salt::None, len: 0 };
+    assert!(matches!(drive.unlock(PasswordOrRaw::Hashed(b"x", empty)), Err(Error::Pbkdf)));
+}
+
+#[test]
+fn lock_ranges_again() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    *tper.range_mut(1) = tper.range(0).clone();
+    let mut drive = OpalDrive::new(tper).unwrap();
+    drive.unlock_ranges(PasswordOrRaw::Raw(PIN), Authority::Admin(1), &[0, 1]).unwrap();
+    assert!(sim(&mut drive).mbr().done);
+
+    drive.lock_ranges(PasswordOrRaw::Raw(PIN), Authority::Admin(1), &[1]).unwrap();
+    let tper = sim(&mut drive);
+    assert!(tper.range(1).is_locked() && !tper.range(0).is_locked());
+    assert!(tper.mbr().done);
+
+    drive.lock_ranges(PasswordOrRaw::Raw(PIN), Authority::Admin(1), &[0]).unwrap();
+    let tper = sim(&mut drive);
+    assert!(tper.range(0).is_locked() && tper.range(0).write_locked);
+    assert!(!tper.mbr().done);
+    assert_eq!(tper.reconnects(), 3);
+    assert!(drive.is_locked().unwrap());
+}
+
+#[test]
+fn unlock_read_only() {
+    let mut drive = OpalDrive::new(SimulatedTper::provisioned(SERIAL, PIN)).unwrap();
+    drive.set_ranges(PasswordOrRaw::Raw(PIN), Authority::Admin(1), &[0], LockingState::ReadOnly).unwrap();
+    let tper = sim(&mut drive);
+    assert!(!tper.range(0).read_locked && tper.range(0).write_locked);
+    // reading the real partition table needs the shadow MBR out of the way
+    assert!(tper.mbr().done);
+    let mut session = OpalSession::start(&mut drive.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(PIN)).unwrap();
+    assert_eq!(session.locking_range_state(0).unwrap(), LockingState::ReadOnly);
+}
+
+#[test]
+fn unlock_read_only_enables_write_lock() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.range_mut(0).write_lock_enabled = false;
+    let mut drive = OpalDrive::new(tper).unwrap();
+    drive.set_ranges(PasswordOrRaw::Raw(PIN), Authority::Admin(1), &[0], LockingState::ReadOnly).unwrap();
+    let tper = sim(&mut drive);
+    assert!(tper.range(0).write_lock_enabled && tper.range(0).write_locked);
+    assert!(!tper.range(0).read_locked);
+}
+
+#[test]
+fn unlock_read_only_as_user() {
+    let mut tper = SimulatedTper::provisioned(SERIAL, PIN);
+    tper.enable_user(1);
+    tper.set_user_pin(1, PIN);
+    tper.grant_range(0, 1);
+    let mut drive = OpalDrive::new(tper).unwrap();
+    // users can't enable WriteLockEnabled, and can't read the row to see that it already is
+    let res = drive.set_ranges(PasswordOrRaw::Raw(PIN), Authority::User(1), &[0], LockingState::ReadOnly);
+    assert!(matches!(res, Err(Error::WriteLockDisabled { locking_range: 0 })));
+    assert!(sim(&mut drive).range(0).is_locked());
+    // but may unlock it read-write
+    drive.set_ranges(PasswordOrRaw::Raw(PIN), Authority::User(1), &[0], LockingState::ReadWrite).unwrap();
+    assert!(!sim(&mut drive).range(0).is_locked());
+}
+
+#[test]
+fn enterprise_lock_band() {
+    let mut drive = OpalDrive::new(SimulatedTper::enterprise(SERIAL, PIN)).unwrap();
+    drive.unlock(PasswordOrRaw::Raw(PIN)).unwrap();
+    drive.lock_ranges(PasswordOrRaw::Raw(PIN), Authority::default(), &[0]).unwrap();
+    assert!(sim(&mut drive).range(0).is_locked());
+}
@@ -0,0 +1,139 @@
+use alloc::vec::Vec;
+
+use crate::command::Value;
+
+/// Column of a table, addressed by number on Opal and by name on Enterprise drives
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub number: u64,
+    pub name: &'static str,
+}
+
+impl Column {
+    pub const fn new(number: u64, name: &'static str) -> Self {
+        Self { number, name }
+    }
+
+    pub(crate) fn key(self, eprise: bool) -> Value {
+        match eprise {
+            true => Value::from(self.name),
+            false => Value::Uint(self.number),
+        }
+    }
+}
+
+pub mod locking {
+    use super::Column;
+
+    pub const RANGE_START: Column = Column::new(3, "RangeStart");
+    pub const RANGE_LENGTH: Column = Column::new(4, "RangeLength");
+    pub const READ_LOCK_ENABLED: Column = Column::new(5, "ReadLockEnabled");
+    pub const WRITE_LOCK_ENABLED: Column = Column::new(6, "WriteLockEnabled");
+    pub const READ_LOCKED: Column = Column::new(7, "ReadLocked");
+    pub const WRITE_LOCKED: Column = Column::new(8, "WriteLocked");
+    pub const LOCK_ON_RESET: Column = Column::new(9, "LockOnReset");
+    pub const ACTIVE_KEY: Column = Column::new(10, "ActiveKey");
+}
+
+pub mod c_pin {
+    use super::Column;
+
+    pub const PIN: Column = Column::new(3, "PIN");
+    pub const TRY_LIMIT: Column = Column::new(5, "TryLimit");
+    pub const TRIES: Column = Column::new(6, "Tries");
+    pub const PERSISTENCE: Column = Column::new(7, "Persistence");
+}
+
+pub mod mbr_control {
+    use super::Column;
+
+    pub const ENABLE: Column = Column::new(1, "Enable");
+    pub const DONE: Column = Column::new(2, "Done");
+    pub const DONE_ON_RESET: Column = Column::new(3, "DoneOnReset");
+}
+
+/// The columns of a row returned by Get, as `(column, value)` pairs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Row(pub Vec<(Value, Value)>);
+
+impl Row {
+    /// Value of `column`, whether it was returned by number or by name
+    pub fn get(&self, column: Column) -> Option<&Value> {
+        self.0.iter().find_map(|(key, value)| match key {
+            Value::Uint(n) if *n == column.number => Some(value),
+            Value::Bytes(name) if name == column.name.as_bytes() => Some(value),
+            _ => None,
+        })
+    }
+
+    pub fn uint(&self, column: Column) -> Option<u64> {
+        self.get(column)?.as_uint()
+    }
+
+    pub fn bool(&self, column: Column) -> Option<bool> {
+        self.get(column)?.as_bool()
+    }
+
+    /// Parses the results of a Get (`[ [ name = value ... ] ]`) or EGet (`[ [ [ name = value ... ] ] ]`)
+    pub(crate) fn from_results(results: &[Value]) -> Option<Self> {
+        let mut row = results.first()?.as_list()?;
+        // Enterprise wraps the row in another list
+        if let [Value::List(inner)] = row {
+            row = inner;
+        }
+        row.iter()
+            .map(|value| match value {
+                Value::Name(name, value) => Some(((**name).clone(), (**value).clone())),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Row)
+    }
+}
+
+/// Row of the Locking table (Band on Enterprise drives)
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LockingRangeRow {
+    pub range_start: u64,
+    pub range_length: u64,
+    pub read_lock_enabled: bool,
+    pub write_lock_enabled: bool,
+    pub read_locked: bool,
+    pub write_locked: bool,
+}
+
+impl LockingRangeRow {
+    pub const FIRST_COLUMN: Column = locking::RANGE_START;
+    pub const LAST_COLUMN: Column = locking::WRITE_LOCKED;
+
+    pub fn from_row(row: &Row) -> Option<Self> {
+        Some(Self {
+            range_start: row.uint(locking::RANGE_START)?,
+            range_length: row.uint(locking::RANGE_LENGTH)?,
+            read_lock_enabled: row.bool(locking::READ_LOCK_ENABLED)?,
+            write_lock_enabled: row.bool(locking::WRITE_LOCK_ENABLED)?,
+            read_locked: row.bool(locking::READ_LOCKED)?,
+            write_locked: row.bool(locking::WRITE_LOCKED)?,
+        })
+    }
+
+    /// The columns to Set; start and length can't be set on the global range
+    pub fn to_values(&self, global: bool) -> Vec<(Column, Value)> {
+        let mut values = Vec::new();
+        if !global {
+            values.push((locking::RANGE_START, Value::Uint(self.range_start)));
+            values.push((locking::RANGE_LENGTH, Value::Uint(self.range_length)));
+        }
+        values.extend([
+            (locking::READ_LOCK_ENABLED, self.read_lock_enabled.into()),
+            (locking::WRITE_LOCK_ENABLED, self.write_lock_enabled.into()),
+            (locking::READ_LOCKED, self.read_locked.into()),
+            (locking::WRITE_LOCKED, self.write_locked.into()),
+        ]);
+        values
+    }
+
+    pub fn is_locked(&self) -> bool {
+        (self.read_lock_enabled && self.read_locked) || (self.write_lock_enabled && self.write_locked)
+    }
+}
@@ -1,5 +1,5 @@
 use alloc::{string::String, vec::Vec};
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use core::cell::RefCell;
 use log::LevelFilter;
 use serde::{Deserialize, Deserializer};
@@ -28,6 +28,8 @@ pub fn load(image_handle: Handle, st: &SystemTable<Boot>) -> crate::Result<Confi
     let buf = crate::util::read_full_file(st, device_handle, cstr16!("config.toml"))?;
     let config: Config = toml::from_slice(&buf)
         .context("error decoding config file as toml")?;
+    config.check_opal_credentials().map_err(crate::error::Error::new_without_source)?;
+    config.check_datastore_keyslots().map_err(crate::error::Error::new_without_source)?;
     log::set_max_level(config.log_level);
     // log::debug!("loaded config = {:#?}", config);
     Ok(config)
@@ -42,10 +44,65 @@ pub struct Config {
     pub keyslot_buffer: RefCell<BTreeMap<String, Vec<u8>>>,
     #[serde(skip)]
     pub luks_masterkey_buffer: RefCell<BTreeMap<String, luks2::SecretMasterKey>>,
+    /// seeded from EFI_RNG_PROTOCOL at startup and the TPers of the opal drives as they are unlocked
+    #[cfg(target_os = "uefi")]
+    #[serde(skip)]
+    pub rng: RefCell<crate::rng::Rng>,
+    /// uuids of the partitions whose opal drive has seeded `rng`
+    #[serde(skip)]
+    pub tper_random_partitions: RefCell<BTreeSet<String>>,
     #[serde(deserialize_with = "deserialize_partitions")]
     pub partitions: BTreeMap<String, Partition>,
     pub boot_entries: Vec<BootEntry>,
     pub log_level: LevelFilter,
+    /// block SID authentication of all OPAL drives before booting, until they are power cycled
+    #[serde(default)]
+    pub opal_block_sid: bool,
+}
+
+impl Config {
+    /// Errors if a partition is unlocked with a `sid` keyslot: SID belongs to the Admin SP, not the Locking SP
+    pub fn check_opal_credentials(&self) -> Result<(), String> {
+        for partition in self.partitions.values() {
+            for (keyslot, _) in partition.opal_credentials() {
+                if self.keyslots.get(keyslot).is_some_and(|ks| ks.authority == OpalAuthority::Sid) {
+                    return Err(format!("partition {}: keyslot {keyslot} has authority `sid`, which can't unlock locking ranges", partition.name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Errors if reading a DataStore keyslot needs the keyslot itself, i.e. it unlocks its own drive,
+    /// directly or through DataStore keyslots of other drives
+    pub fn check_datastore_keyslots(&self) -> Result<(), String> {
+        for keyslot in self.keyslots.keys() {
+            self.check_datastore_keyslot(&mut vec![keyslot.as_str()])?;
+        }
+        Ok(())
+    }
+
+    fn check_datastore_keyslot<'a>(&'a self, path: &mut Vec<&'a str>) -> Result<(), String> {
+        let Some(KeyslotSource::DataStore(datastore)) = path.last().and_then(|name| self.keyslots.get(*name)).map(|ks| &ks.source) else {
+            return Ok(());
+        };
+        // the drive is unlocked with the keyslots of its partition before its DataStore is read
+        let credentials: Vec<&str> = self.partitions.values()
+            .filter(|part| part.uuid == datastore.datastore)
+            .flat_map(|part| part.opal_credentials())
+            .map(|(credential, _)| credential)
+            .collect();
+        for credential in credentials {
+            let cycle = path.contains(&credential);
+            path.push(credential);
+            if cycle {
+                return Err(format!("DataStore keyslots depend on each other: {}", path.join(" -> ")));
+            }
+            self.check_datastore_keyslot(path)?;
+            path.pop();
+        }
+        Ok(())
+    }
 }
 
 fn deserialize_keyslots<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Keyslot>, D::Error> {
@@ -61,6 +118,84 @@ fn deserialize_partitions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<
 pub struct Keyslot {
     pub name: String,
     pub source: KeyslotSource,
+    /// OPAL signing authority used with this keyslot, e.g. `"admin1"` (the default) or `"user2"`
+    #[serde(default)]
+    pub authority: OpalAuthority,
+    /// how OPAL derives the PIN from the password of a `stdin` keyslot, sedutil's scheme if not set
+    pub opal_hash: Option<OpalHash>,
+    /// unlock the OPAL locking ranges of this keyslot read-only, the drive itself rejects all writes
+    #[serde(default)]
+    pub opal_read_only: bool,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct OpalHash {
+    pub algorithm: OpalHashAlgorithm,
+    #[serde(default = "default_opal_hash_iterations")]
+    pub iterations: u32,
+    #[serde(default)]
+    pub salt: OpalSalt,
+    /// of the PIN, in bytes
+    #[serde(default = "default_opal_hash_length")]
+    pub length: usize,
+}
+fn default_opal_hash_iterations() -> u32 {
+    75000
+}
+fn default_opal_hash_length() -> usize {
+    32
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OpalHashAlgorithm {
+    /// the password is the PIN
+    Plain,
+    Pbkdf2Sha1,
+    Pbkdf2Sha256,
+    Pbkdf2Sha512,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OpalSalt {
+    /// the serial number of the drive
+    #[default]
+    Serial,
+    None,
+    Fixed(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpalAuthority {
+    Sid,
+    Admin(u8),
+    User(u8),
+}
+impl Default for OpalAuthority {
+    fn default() -> Self {
+        OpalAuthority::Admin(1)
+    }
+}
+impl<'de> Deserialize<'de> for OpalAuthority {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let s = String::deserialize(deserializer)?;
+        if s == "sid" {
+            return Ok(OpalAuthority::Sid);
+        }
+        let (kind, n) = match s.find(|c: char| c.is_ascii_digit()) {
+            Some(i) => s.split_at(i),
+            None => return Err(D::Error::custom(format_args!("invalid authority `{s}`, expected e.g. `sid`, `admin1` or `user2`"))),
+        };
+        let n = n.parse::<u8>().ok().filter(|&n| n != 0)
+            .ok_or_else(|| D::Error::custom(format_args!("invalid authority number in `{s}`")))?;
+        match kind {
+            "admin" => Ok(OpalAuthority::Admin(n)),
+            "user" => Ok(OpalAuthority::User(n)),
+            _ => Err(D::Error::custom(format_args!("unknown authority `{s}`, expected `sid`, `adminN` or `userN`"))),
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -69,6 +204,7 @@ pub enum KeyslotSource {
     #[serde(deserialize_with = "deserialize_stdin")]
     Stdin,
     File(File),
+    DataStore(DataStore),
 }
 fn deserialize_stdin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(), D::Error> {
     #[derive(Deserialize)]
@@ -85,12 +221,40 @@ pub struct File {
     pub file: String,
 }
 
+/// Bytes `start..end` of the DataStore table of the OPAL drive with serial number `datastore`.
+/// It's read right after the drive is unlocked, authenticating with the first keyslot of its partition.
+#[derive(Debug, serde::Deserialize)]
+pub struct DataStore {
+    pub datastore: String,
+    pub start: u32,
+    pub end: u32,
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct Partition {
     pub name: String,
     pub parent: Option<String>,
     pub uuid: String,
     pub keyslot: Option<String>,
+    /// OPAL drives only: credentials for individual locking ranges, overrides `keyslot`
+    #[serde(default)]
+    pub opal_ranges: Vec<OpalRanges>,
+}
+
+impl Partition {
+    /// Keyslots with the locking ranges they unlock, `keyslot` unlocks the global range
+    pub fn opal_credentials(&self) -> Vec<(&str, &[u8])> {
+        if !self.opal_ranges.is_empty() {
+            return self.opal_ranges.iter().map(|r| (r.keyslot.as_str(), r.ranges.as_slice())).collect();
+        }
+        self.keyslot.iter().map(|keyslot| (keyslot.as_str(), &[0u8][..])).collect()
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OpalRanges {
+    pub keyslot: String,
+    pub ranges: Vec<u8>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -57,7 +57,7 @@ impl<'a> SecureProtocol for AtaProtocol<'a> {
         cmd_id: u16,
         buffer: &mut [u8],
     ) -> Result<(), Self::Error> {
-        let data = self.passthru.do_io(self.port, self.port_multiplier_port, IoMode::Recv { protocol, cmd_id }).map_err(|error| UefiError { error })?;
+        let data = self.passthru.do_io(self.port, self.port_multiplier_port, IoMode::Recv { protocol, cmd_id, len: buffer.len() }).map_err(|error| UefiError { error })?;
         let s = core::cmp::min(data.len(), buffer.len());
         buffer[..s].copy_from_slice(&data[..s]);
         Ok(())
@@ -73,6 +73,10 @@ impl<'a> SecureProtocol for AtaProtocol<'a> {
         Ok(())
     }
 
+    fn stall(&mut self, microseconds: usize) {
+        self.st.boot_services().stall(microseconds);
+    }
+
     fn align(&self) -> usize {
         unsafe { (*self.passthru.mode).io_align as usize }
     }
@@ -137,7 +141,7 @@ pub enum AtaCommand {
 #[derive(Clone, Copy)]
 enum IoMode<'a> {
     Identify,
-    Recv { protocol: u8, cmd_id: u16 },
+    Recv { protocol: u8, cmd_id: u16, len: usize },
     Send {
         protocol: u8,
         cmd_id: u16,
@@ -163,7 +167,7 @@ impl AtaPassthru {
         };
         match mode {
             IoMode::Identify => (),
-            IoMode::Recv { protocol, cmd_id } | IoMode::Send { protocol, cmd_id, .. } => {
+            IoMode::Recv { protocol, cmd_id, .. } | IoMode::Send { protocol, cmd_id, .. } => {
                 acb.features = protocol;
                 /*
                 acb.cylinder_high = cmd_id as u8;
@@ -180,7 +184,12 @@ impl AtaPassthru {
             IoMode::Send { .. } => AtaPassthruProtocol::PioDataOut,
         };
 
-        let mut return_data = alloc_init_aligned(2048, align);
+        // IF-RECV transfers whole sectors, the opal crate asks for more than 2048 bytes on large responses
+        let return_len = match mode {
+            IoMode::Recv { len, .. } => len.div_ceil(512).max(4) * 512,
+            _ => 2048,
+        };
+        let mut return_data = alloc_init_aligned(return_len, align);
         let mut packet = CommandPacket {
             protocol,
             length: AtaPassthruLength::BYTES | AtaPassthruLength::SECTOR_COUNT,
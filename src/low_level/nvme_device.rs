@@ -140,4 +140,8 @@ impl<'a> SecureProtocol for RestartableNvmeDevice<'a> {
             .map_err(|error| UefiError { error })?;
         Ok(())
     }
+
+    fn stall(&mut self, microseconds: usize) {
+        self.st.boot_services().stall(microseconds);
+    }
 }
@@ -12,7 +12,6 @@ extern crate rlibc;
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use either::Either;
 use low_level::ata_passthru::{AtaPassthru, AtaProtocol};
 use opal::PasswordOrRaw;
 use uefi::proto::device_path::text::{DisplayOnly, AllowShortcuts};
@@ -29,7 +28,7 @@ use luks2::{LuksDevice, LuksHeader};
 use luks2::error::LuksError;
 use lvm2::Lvm2;
 use positioned_io2::SeekWrapper;
-use uefi::Handle;
+use uefi::{guid, Guid, Handle};
 use uefi::table::{Boot, SystemTable};
 use uefi::{CStr16, CString16, prelude::*, proto::{
     device_path::DevicePath,
@@ -50,7 +49,7 @@ use crate::{
     error::{Error, Result, Context},
     util::sleep,
 };
-use crate::config::{AdditionalInitrdFile, BootEntry, File, Initrd, Keyslot, KeyslotSource, Partition};
+use crate::config::{AdditionalInitrdFile, BootEntry, DataStore, File, Initrd, Keyslot, KeyslotSource, OpalAuthority, OpalHash, OpalHashAlgorithm, OpalSalt, Partition};
 use crate::error::ErrorSource;
 use crate::io::{BlockIoReader, PartialReader, OptimizedSeek, ReadSeek, IgnoreWriteWrapper};
 
@@ -60,6 +59,7 @@ pub mod util;
 pub mod low_level;
 mod ui;
 mod io;
+mod rng;
 
 #[entry]
 fn main(image_handle: Handle, mut st: SystemTable<Boot>) -> Status {
@@ -82,6 +82,7 @@ fn main(image_handle: Handle, mut st: SystemTable<Boot>) -> Status {
         }
     };
     log::trace!("loaded config");
+    seed_rng(&st, &config);
     loop {
         match run(image_handle, &mut st, &config) {
            Ok(()) => (),
@@ -107,6 +108,11 @@ fn run(image_handle: Handle, st: &SystemTable<Boot>, config: &Config) -> Result
 
     let mut options: Vec<_> = config.boot_entries.iter().map(|e| (true, e.name.clone())).collect();
     options.push((true, "Unlock configured opal drives".to_string()));
+    options.push((true, "Lock configured opal drives".to_string()));
+    options.push((true, "Lock opal locking ranges".to_string()));
+    options.push((true, "Change opal password".to_string()));
+    options.push((true, "Erase opal locking ranges".to_string()));
+    options.push((true, "PSID revert (erases the drive)".to_string()));
     log::trace!("created chooser-options");
     let selected = ui::choose(st, &options)?;
     let boot_entry_len = config.boot_entries.len();
@@ -117,6 +123,11 @@ fn run(image_handle: Handle, st: &SystemTable<Boot>, config: &Config) -> Result
             handle_boot_entry(st, image_handle, config, boot_entry)?;
         },
         i if i == boot_entry_len => handle_unlock_configured_opal_drives(st, config)?,
+        i if i == boot_entry_len + 1 => handle_lock_configured_opal_drives(st, config)?,
+        i if i == boot_entry_len + 2 => handle_lock_opal_ranges(st, config)?,
+        i if i == boot_entry_len + 3 => handle_change_opal_password(st, config)?,
+        i if i == boot_entry_len + 4 => handle_erase_opal_ranges(st, config)?,
+        i if i == boot_entry_len + 5 => handle_psid_revert(st)?,
         i => unreachable!("unknown boot entry selection {}", i),
     }
 
@@ -153,6 +164,35 @@ fn run(image_handle: Handle, st: &SystemTable<Boot>, config: &Config) -> Result
 }
 
 
+/// Evaluates `$body` with `$drive` bound to the opal drive behind the BlockIO handle, NVMe or ATA.
+/// `$body` is a `Result`, the whole expression a `Result<Option<_>>` that is `None` if the handle
+/// is neither; `?` within `$body` returns from the expression, not from the enclosing function.
+macro_rules! with_opal_drive {
+    ($st:expr, $blockio_handle:expr, |$drive:ident| $body:expr) => {
+        (|| -> Result<Option<_>> {
+            let (st, blockio_handle): (&SystemTable<Boot>, Handle) = ($st, $blockio_handle);
+            match try_get_nvme_device(st, blockio_handle)? {
+                Some(nvme) => {
+                    #[allow(unused_mut)]
+                    let mut $drive = opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle))
+                        .map_err(|e| Error::new(e, "open opal"))?;
+                    $body.map(Some)
+                }
+                None => match try_get_ata_device(st, blockio_handle)? {
+                    #[allow(unused_mut)]
+                    Some(mut $drive) => $body.map(Some),
+                    None => Ok(None),
+                },
+            }
+        })()
+    };
+}
+
+/// for a drive found by its serial number before, which isn't an opal drive anymore
+fn opal_drive_gone(serial: &str) -> Error {
+    Error::new_without_source(format!("drive {serial} is gone"))
+}
+
 fn try_get_ata_device(st: &SystemTable<Boot>, blockio_handle: Handle) -> Result<Option<opal::OpalDrive<AtaProtocol<'_>>>> {
     let params = OpenProtocolParams { handle: blockio_handle, agent: st.boot_services().image_handle(), controller: None };
     let device_path = unsafe {
@@ -187,26 +227,28 @@ fn try_get_ata_device(st: &SystemTable<Boot>, blockio_handle: Handle) -> Result<
 
 
 fn handle_unlock_configured_opal_drives(st: &SystemTable<Boot>, config: &Config) -> Result<()> {
+    handle_configured_opal_drives(st, config, false)
+}
+
+fn handle_lock_configured_opal_drives(st: &SystemTable<Boot>, config: &Config) -> Result<()> {
+    handle_configured_opal_drives(st, config, true)?;
+    // don't keep the passwords and LUKS master keys around for whoever gets the machine next
+    config.keyslot_buffer.borrow_mut().clear();
+    config.luks_masterkey_buffer.borrow_mut().clear();
+    let mut st = unsafe { st.unsafe_clone() };
+    st.stdout().write_str("Configured opal drives locked\r\n").unwrap();
+    Ok(())
+}
+
+fn handle_configured_opal_drives(st: &SystemTable<Boot>, config: &Config, lock: bool) -> Result<()> {
     for (i, (blockio_handle, start_lba, end_lba)) in block_devices(st)?.into_iter().enumerate() {
         log::debug!("probing blockio #{i} {start_lba:#x} - {end_lba:#x}");
 
         // probe OPAL
-        let mut dev = match try_get_nvme_device(st, blockio_handle)? {
-            Some(nvme) => Either::Left(nvme),
-            None => match try_get_ata_device(st, blockio_handle)? {
-                Some(ata) => Either::Right(ata),
-                None => continue,
-            },
-        };
-
-        let serial = match &mut dev {
-            Either::Left(nvme) => nvme.serial_num(),
-            Either::Right(ata) => ata.serial(),
+        let serial = match opal_drive_serial(st, blockio_handle) {
+            Some(serial) => serial,
+            None => continue,
         };
-
-        let serial = core::str::from_utf8(serial)
-            .context("can't convert disk serial number to UTF8")?
-            .trim();
         log::debug!("found disk with serial: `{}`", serial);
 
         let partition = match config.partitions.values().find(|part| part.uuid == serial) {
@@ -214,17 +256,225 @@ fn handle_unlock_configured_opal_drives(st: &SystemTable<Boot>, config: &Config)
             None => continue,
         };
 
-        // decrypt
-        let keyslot = partition.keyslot.as_deref().unwrap();
-        let keyslot = &config.keyslots[keyslot];
-        match dev {
-            Either::Left(nvme) => unlock_opal(st, opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)).map_err(|e| Error::new(e, "open opal"))?, config, keyslot)?,
-            Either::Right(ata) => unlock_opal(st, ata, config, keyslot)?,
+        with_opal_drive!(st, blockio_handle, |drive| match lock {
+            false => unlock_opal(st, drive, config, partition),
+            true => lock_opal(st, drive, config, partition),
+        })?.ok_or_else(|| opal_drive_gone(&serial))?;
+    }
+    Ok(())
+}
+
+fn handle_change_opal_password(st: &SystemTable<Boot>, config: &Config) -> Result<()> {
+    // only passwords typed in by the user can be changed here, keyfiles are used as raw key
+    let mut credentials: Vec<(Option<&Partition>, &Keyslot)> = config.partitions.values()
+        .flat_map(|partition| partition.opal_credentials().into_iter().map(move |(keyslot, _)| (Some(partition), &config.keyslots[keyslot])))
+        .filter(|(_, keyslot)| matches!(keyslot.source, KeyslotSource::Stdin))
+        .collect();
+    // SID unlocks no partition, it owns the whole drive
+    credentials.extend(config.keyslots.values()
+        .filter(|keyslot| keyslot.authority == OpalAuthority::Sid && matches!(keyslot.source, KeyslotSource::Stdin))
+        .map(|keyslot| (None, keyslot)));
+    if credentials.is_empty() {
+        return Err(Error::new_without_source("no opal keyslot with a password configured"));
+    }
+    let mut options: Vec<_> = credentials.iter()
+        .map(|(partition, keyslot)| match partition {
+            Some(partition) => (true, format!("{}: keyslot {} ({:?})", partition.name, keyslot.name, keyslot.authority)),
+            None => (true, format!("Any drive: keyslot {} (SID)", keyslot.name)),
+        })
+        .collect();
+    options.push((true, "Cancel".to_string()));
+    let Some(&(partition, keyslot)) = credentials.get(ui::choose(st, &options)?) else { return Ok(()) };
+
+    let (blockio_handle, serial) = match partition {
+        Some(partition) => (find_opal_drive(st, &partition.uuid)?, partition.uuid.clone()),
+        None => match choose_opal_drive(st)? {
+            Some(drive) => drive,
+            None => return Ok(()),
+        },
+    };
+    with_opal_drive!(st, blockio_handle, |drive| change_opal_password(st, drive, config, keyslot))?
+        .ok_or_else(|| opal_drive_gone(&serial))
+}
+
+/// lets the user choose any opal drive, configured or not, `None` on Cancel
+fn choose_opal_drive(st: &SystemTable<Boot>) -> Result<Option<(Handle, String)>> {
+    let mut drives = Vec::new();
+    for (blockio_handle, _, _) in block_devices(st)? {
+        if let Some(serial) = opal_drive_serial(st, blockio_handle) {
+            drives.push((blockio_handle, serial));
+        }
+    }
+    let mut options: Vec<_> = drives.iter().map(|(_, serial)| (true, format!("Drive {serial}"))).collect();
+    options.push((true, "Cancel".to_string()));
+    let choice = ui::choose(st, &options)?;
+    Ok((choice < drives.len()).then(|| drives.swap_remove(choice)))
+}
+
+/// BlockIO handle of the opal drive with the given serial number
+fn find_opal_drive(st: &SystemTable<Boot>, serial: &str) -> Result<Handle> {
+    for (blockio_handle, _, _) in block_devices(st)? {
+        if opal_drive_serial(st, blockio_handle).as_deref() == Some(serial) {
+            return Ok(blockio_handle);
+        }
+    }
+    Err(Error::new_without_source(format!("no opal drive with serial `{serial}` found")))
+}
+
+/// serial number of the drive behind the BlockIO handle, `None` if it's no NVMe or ATA drive;
+/// one drive failing to answer mustn't hide the others, so errors are logged and the drive is skipped
+fn opal_drive_serial(st: &SystemTable<Boot>, blockio_handle: Handle) -> Option<String> {
+    let serial = match try_get_nvme_device(st, blockio_handle) {
+        Ok(Some(nvme)) => Ok(Some(nvme.serial_num().to_vec())),
+        Ok(None) => try_get_ata_device(st, blockio_handle).map(|ata| ata.map(|mut ata| ata.serial().to_vec())),
+        Err(e) => Err(e),
+    };
+    match serial {
+        Ok(serial) => serial.map(|serial| String::from_utf8_lossy(&serial).trim().to_string()),
+        Err(e) => {
+            log::warn!("skipping drive that can't be probed: {e:?}");
+            None
+        }
+    }
+}
+
+fn change_opal_password<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot) -> Result<()>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let mut stdout = unsafe { st.unsafe_clone() };
+    loop {
+        // every wrong old password costs a try
+        if show_remaining_tries(st, &mut secure_device, keyslot) == Some(0) {
+            return Ok(());
+        }
+        stdout.stdout().write_str(&format!("Old password for keyslot {} (empty to cancel): ", keyslot.name)).unwrap();
+        let old = ui::password(st)?;
+        if old.is_empty() {
+            return Ok(());
         }
+        stdout.stdout().write_str("New password: ").unwrap();
+        let new = ui::password(st)?;
+        stdout.stdout().write_str("Repeat new password: ").unwrap();
+        if ui::password(st)? != new {
+            log::error!("Passwords don't match, try again!");
+            continue;
+        }
+
+        let authority = opal_authority(keyslot.authority);
+        match secure_device.change_password(authority, password_or_raw(keyslot, old.as_bytes()), password_or_raw(keyslot, new.as_bytes())) {
+            Ok(()) => {
+                config.keyslot_buffer.borrow_mut().remove(&keyslot.name);
+                stdout.stdout().write_str("Password changed\r\n").unwrap();
+                return Ok(());
+            }
+            Err(opal::Error::Authentication { .. }) => {
+                log::error!("Invalid Password, try again!");
+            }
+            Err(e) => return Err(Error::new(e, "error changing opal password")),
+        }
+    }
+}
+
+/// lets the user choose the locking ranges of one of the configured keyslots, `None` on Cancel
+fn choose_opal_ranges<'a>(st: &SystemTable<Boot>, config: &'a Config) -> Result<Option<(&'a Partition, &'a Keyslot, &'a [u8])>> {
+    let credentials: Vec<(&Partition, &Keyslot, &[u8])> = config.partitions.values()
+        .flat_map(|partition| partition.opal_credentials().into_iter().map(move |(keyslot, ranges)| (partition, &config.keyslots[keyslot], ranges)))
+        .collect();
+    let mut options: Vec<_> = credentials.iter()
+        .map(|(partition, keyslot, ranges)| (true, format!("{}: locking ranges {ranges:?} (keyslot {})", partition.name, keyslot.name)))
+        .collect();
+    options.push((true, "Cancel".to_string()));
+    Ok(credentials.get(ui::choose(st, &options)?).copied())
+}
+
+fn handle_lock_opal_ranges(st: &SystemTable<Boot>, config: &Config) -> Result<()> {
+    let Some((partition, keyslot, ranges)) = choose_opal_ranges(st, config)? else { return Ok(()) };
+
+    let blockio_handle = find_opal_drive(st, &partition.uuid)?;
+    with_opal_drive!(st, blockio_handle, |drive| set_opal_ranges(st, &mut drive, config, keyslot, ranges, opal::LockingState::Locked))?
+        .ok_or_else(|| opal_drive_gone(&partition.uuid))
+}
+
+fn handle_erase_opal_ranges(st: &SystemTable<Boot>, config: &Config) -> Result<()> {
+    let Some((partition, keyslot, ranges)) = choose_opal_ranges(st, config)? else { return Ok(()) };
+
+    // confirm twice, the old keys are gone for good
+    let mut stdout = unsafe { st.unsafe_clone() };
+    stdout.stdout().write_str(&format!("ALL DATA in locking ranges {ranges:?} of {} will be lost! Type `yes` to continue: ", partition.name)).unwrap();
+    if ui::line(st)? != "yes" {
+        return Ok(());
+    }
+    stdout.stdout().write_str(&format!("Type `erase {}` to erase the locking ranges: ", partition.name)).unwrap();
+    if ui::line(st)? != format!("erase {}", partition.name) {
+        return Ok(());
     }
+
+    let blockio_handle = find_opal_drive(st, &partition.uuid)?;
+    with_opal_drive!(st, blockio_handle, |drive| erase_opal_ranges(st, drive, config, keyslot, ranges))?
+        .ok_or_else(|| opal_drive_gone(&partition.uuid))
+}
+
+fn erase_opal_ranges<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot, ranges: &[u8]) -> Result<()>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let password = get_password_of_keyslot(st, config, keyslot, Cache::Cached)?;
+    match secure_device.erase_ranges(password_or_raw(keyslot, &password), opal_authority(keyslot.authority), ranges) {
+        Ok(()) => (),
+        Err(opal::Error::Authentication { .. }) => {
+            log::error!("Invalid Password for keyslot {}", keyslot.name);
+            config.keyslot_buffer.borrow_mut().remove(&keyslot.name);
+            return Ok(());
+        }
+        Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+            log::error!("Keyslot {} may not erase locking ranges {ranges:?}, e.g. because it's a user", keyslot.name);
+            return Ok(());
+        }
+        Err(e) => return Err(Error::new(e, "error erasing locking ranges")),
+    }
+    let mut st = unsafe { st.unsafe_clone() };
+    st.stdout().write_str("Locking ranges erased\r\n").unwrap();
     Ok(())
 }
 
+fn handle_psid_revert(st: &SystemTable<Boot>) -> Result<()> {
+    // offer every opal drive, not only configured ones; their passwords might be what got lost
+    let Some((blockio_handle, serial)) = choose_opal_drive(st)? else { return Ok(()) };
+
+    let mut stdout = unsafe { st.unsafe_clone() };
+    stdout.stdout().write_str("PSID from the drive label: ").unwrap();
+    let psid = ui::line(st)?;
+
+    // confirm twice, there's no way back
+    stdout.stdout().write_str(&format!("ALL DATA on drive {serial} will be lost! Type `yes` to continue: ")).unwrap();
+    if ui::line(st)? != "yes" {
+        return Ok(());
+    }
+    stdout.stdout().write_str(&format!("Type `erase {serial}` to revert the drive to factory state: ")).unwrap();
+    if ui::line(st)? != format!("erase {serial}") {
+        return Ok(());
+    }
+
+    with_opal_drive!(st, blockio_handle, |drive| psid_revert_opal(st, drive, &psid))?
+        .ok_or_else(|| opal_drive_gone(&serial))
+}
+
+fn psid_revert_opal<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, psid: &str) -> Result<()>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    match secure_device.psid_revert(psid.as_bytes()) {
+        Ok(()) => {
+            let mut st = unsafe { st.unsafe_clone() };
+            st.stdout().write_str("Drive reverted to factory state\r\n").unwrap();
+            Ok(())
+        }
+        Err(opal::Error::Authentication { .. }) => {
+            log::error!("Invalid PSID, the drive is unchanged");
+            Ok(())
+        }
+        Err(e) => Err(Error::new(e, "error during PSID revert")),
+    }
+}
+
 fn find_boot_partition(st: &SystemTable<Boot>) -> Result<Option<Handle>> {
     log::info!("reconnecting all controllers to hopefully make ParitionInfo show up");
     for (blockio_handle, _, _) in block_devices(st)? {
@@ -325,6 +575,18 @@ fn handle_boot_entry(st: &SystemTable<Boot>, image_handle: Handle, config: &Conf
         }
     }
 
+    let mut rng = config.rng.borrow_mut();
+    if rng.is_seeded() {
+        if let Err(e) = install_linux_random_seed(st, &mut rng) {
+            log::warn!("can't pass a random seed to the kernel: {e:?}");
+        }
+    } else {
+        log::warn!("no entropy source found, the kernel gets no random seed");
+    }
+    if config.opal_block_sid {
+        block_sid(st);
+    }
+
     st.boot_services()
         .start_image(loaded_image_handle)
         .context("error booting loaded bootimage")?;
@@ -332,6 +594,80 @@ fn handle_boot_entry(st: &SystemTable<Boot>, image_handle: Handle, config: &Conf
     Ok(())
 }
 
+/// Blocks SID authentication on every opal drive supporting it, so the booted OS can neither take
+/// ownership of drives whose SID is still MSID nor brute-force SID
+fn block_sid(st: &SystemTable<Boot>) {
+    let block_devices = match block_devices(st) {
+        Ok(block_devices) => block_devices,
+        Err(e) => {
+            log::error!("can't list block devices to block SID: {e:?}");
+            return;
+        }
+    };
+    for (blockio_handle, _, _) in block_devices {
+        let res = with_opal_drive!(st, blockio_handle, |drive| block_sid_opal(&mut drive).map_err(|e| Error::new(e, "Block SID")));
+        if let Err(e) = res {
+            log::error!("can't block SID: {e:?}");
+        }
+    }
+}
+
+fn block_sid_opal<P: opal::SecureProtocol>(drive: &mut opal::OpalDrive<P>) -> core::result::Result<(), opal::Error<P::Error>> {
+    if drive.info().block_sid.is_none() {
+        log::debug!("drive `{}` doesn't support Block SID", String::from_utf8_lossy(drive.serial()).trim());
+        return Ok(());
+    }
+    drive.block_sid(false)
+}
+
+/// Seeds the RNG of the config from EFI_RNG_PROTOCOL, the opal drives add theirs when they are unlocked
+fn seed_rng(st: &SystemTable<Boot>, config: &Config) {
+    match efi_random(st) {
+        Ok(random) => config.rng.borrow_mut().mix(&random),
+        Err(e) => log::debug!("no randomness from EFI_RNG_PROTOCOL: {e:?}"),
+    }
+}
+
+fn efi_random(st: &SystemTable<Boot>) -> Result<[u8; 32]> {
+    let handle = st
+        .boot_services()
+        .get_handle_for_protocol::<uefi::proto::rng::Rng>()
+        .context("no EFI_RNG_PROTOCOL")?;
+    let mut efi_rng = st
+        .boot_services()
+        .open_protocol_exclusive::<uefi::proto::rng::Rng>(handle)
+        .context("can't open EFI_RNG_PROTOCOL")?;
+    let mut random = [0; 32];
+    efi_rng.get_rng(None, &mut random).context("EFI_RNG_PROTOCOL failed")?;
+    Ok(random)
+}
+
+/// LINUX_EFI_RANDOM_SEED_TABLE_GUID
+const LINUX_RANDOM_SEED_TABLE: Guid = guid!("1ce1e5bc-7ceb-42f2-81e5-8aadf180f57b");
+const LINUX_RANDOM_SEED_LEN: usize = 32;
+
+/// Installs the random seed table the EFI stub of Linux mixes into the kernel's entropy pool,
+/// a seed of an earlier boot stage is mixed into ours
+fn install_linux_random_seed(st: &SystemTable<Boot>, rng: &mut rng::Rng) -> Result<()> {
+    // struct linux_efi_random_seed { u32 size; u8 bits[]; }
+    if let Some(entry) = st.config_table().iter().find(|entry| entry.guid == LINUX_RANDOM_SEED_TABLE) {
+        unsafe {
+            let size = (entry.address as *const u32).read_unaligned() as usize;
+            rng.mix(slice::from_raw_parts((entry.address as *const u8).add(4), size.min(512)));
+        }
+    }
+    let table = st
+        .boot_services()
+        .allocate_pool(MemoryType::ACPI_RECLAIM, 4 + LINUX_RANDOM_SEED_LEN)
+        .context("can't allocate the random seed table")?;
+    let seed = unsafe { slice::from_raw_parts_mut(table, 4 + LINUX_RANDOM_SEED_LEN) };
+    seed[..4].copy_from_slice(&(LINUX_RANDOM_SEED_LEN as u32).to_ne_bytes());
+    rng.fill_bytes(&mut seed[4..]);
+    unsafe { st.boot_services().install_configuration_table(&LINUX_RANDOM_SEED_TABLE, table as *const _) }
+        .context("can't install the random seed table")?;
+    Ok(())
+}
+
 fn construct_initramfs(st: &SystemTable<Boot>, config: &Config, initrd: &Option<Initrd>, additional_initrd_files: &Option<Vec<AdditionalInitrdFile>>) -> Result<(u64, usize)> {
     let mut initramfs = Initramfs::new();
 
@@ -421,40 +757,204 @@ fn try_get_nvme_device(st: &SystemTable<Boot>, blockio_handle: Handle) -> Result
     }
 }
 
-/// returns if it was already unlocked
-fn unlock_opal<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot) -> Result<()>
+/// unlocks the locking ranges of every keyslot of the partition, does nothing if the drive isn't locked
+/// unless the keyslot is read-only;
+/// afterwards reads the DataStore keyslots of the drive and, once per boot, random bytes for the RNG seed
+fn unlock_opal<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, config: &Config, partition: &Partition) -> Result<()>
 where opal::Error<P::Error>: Into<ErrorSource>
 {
-    if !secure_device.was_locked() {
-        return Ok(());
+    log::debug!("level 0 discovery: {:?}", secure_device.info());
+    log::debug!("negotiated properties: {:?}", secure_device.properties());
+    if !secure_device.media_encryption() {
+        log::warn!("{}: {:?} drive doesn't encrypt its data, locking only guards access to it", partition.name, secure_device.ssc());
+    }
+    let was_locked = secure_device.was_locked();
+    for (keyslot, ranges) in partition.opal_credentials() {
+        let keyslot = &config.keyslots[keyslot];
+        // read-only keyslots are applied even to unlocked drives, they must never be left writable
+        if keyslot.opal_read_only {
+            log::debug!("{}: unlocking locking ranges {ranges:?} read-only with keyslot {}", partition.name, keyslot.name);
+            set_opal_ranges(st, &mut secure_device, config, keyslot, ranges, opal::LockingState::ReadOnly)?;
+        } else if was_locked {
+            log::debug!("{}: unlocking locking ranges {ranges:?} with keyslot {}", partition.name, keyslot.name);
+            set_opal_ranges(st, &mut secure_device, config, keyslot, ranges, opal::LockingState::ReadWrite)?;
+        }
     }
+    read_opal_datastore_keyslots(st, &mut secure_device, config, partition)?;
+    // the drive is at hand anyway, no need to probe all of them for the RNG seed at boot
+    if !config.tper_random_partitions.borrow().contains(&partition.uuid) {
+        match secure_device.random(32) {
+            Ok(random) => {
+                config.rng.borrow_mut().mix(&random);
+                config.tper_random_partitions.borrow_mut().insert(partition.uuid.clone());
+            }
+            Err(e) => {
+                let e: ErrorSource = e.into();
+                log::debug!("{}: no randomness from the TPer: {e:?}", partition.name);
+            }
+        }
+    }
+    Ok(())
+}
 
+/// locks the locking ranges of every keyslot of the partition and re-enables the MBR shadow
+fn lock_opal<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, config: &Config, partition: &Partition) -> Result<()>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    for (keyslot, ranges) in partition.opal_credentials() {
+        let keyslot = &config.keyslots[keyslot];
+        log::debug!("{}: locking locking ranges {ranges:?} with keyslot {}", partition.name, keyslot.name);
+        set_opal_ranges(st, &mut secure_device, config, keyslot, ranges, opal::LockingState::Locked)?;
+    }
+    Ok(())
+}
+
+/// reads the keyslots stored in the DataStore of the drive into the keyslot buffer
+fn read_opal_datastore_keyslots<P: opal::SecureProtocol>(st: &SystemTable<Boot>, secure_device: &mut opal::OpalDrive<P>, config: &Config, partition: &Partition) -> Result<()>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let datastores: Vec<(&Keyslot, &DataStore)> = config.keyslots.values()
+        .filter_map(|keyslot| match &keyslot.source {
+            KeyslotSource::DataStore(datastore) if datastore.datastore == partition.uuid => Some((keyslot, datastore)),
+            _ => None,
+        })
+        .filter(|(keyslot, _)| !config.keyslot_buffer.borrow().contains_key(&keyslot.name))
+        .collect();
+    let Some(&(credential, _)) = partition.opal_credentials().first() else { return Ok(()) };
+    let credential = &config.keyslots[credential];
+
+    for (keyslot, datastore) in datastores {
+        let len = datastore.end.checked_sub(datastore.start)
+            .ok_or_else(|| Error::new_without_source(format!("keyslot {}: DataStore end is before start", keyslot.name)))?;
+        log::debug!("{}: reading keyslot {} from the DataStore with keyslot {}", partition.name, keyslot.name, credential.name);
+        let key = with_opal_password(st, secure_device, config, credential, "error reading the opal DataStore", |secure_device, password, authority| {
+            secure_device.read_datastore(password, authority, datastore.start, len as usize)
+        })?;
+        config.keyslot_buffer.borrow_mut().insert(keyslot.name.clone(), key);
+    }
+    Ok(())
+}
+
+/// sets the locking ranges to `state` with the keyslot, asking for the password again if it's wrong
+fn set_opal_ranges<P: opal::SecureProtocol>(st: &SystemTable<Boot>, secure_device: &mut opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot, ranges: &[u8], state: opal::LockingState) -> Result<()>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    with_opal_password(st, secure_device, config, keyslot, "efi error trying to set the locking state of the device", |secure_device, password, authority| {
+        secure_device.set_ranges(password, authority, ranges, state)
+    })
+}
+
+/// runs `op` with the password of the keyslot, asking for it again while it's wrong;
+/// a wrong key from a file or DataStore is an error, a locked out authority resets the machine
+fn with_opal_password<P: opal::SecureProtocol, T>(
+    st: &SystemTable<Boot>,
+    secure_device: &mut opal::OpalDrive<P>,
+    config: &Config,
+    keyslot: &Keyslot,
+    context: &str,
+    mut op: impl FnMut(&mut opal::OpalDrive<P>, PasswordOrRaw<'_>, opal::Authority) -> core::result::Result<T, opal::Error<P::Error>>,
+) -> Result<T>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
     let mut cached = Cache::Cached;
     loop {
+        let prompts = matches!(keyslot.source, KeyslotSource::Stdin)
+            && (matches!(cached, Cache::Discard) || !config.keyslot_buffer.borrow().contains_key(&keyslot.name));
+        // no point asking for a password the drive won't even check
+        if prompts && show_remaining_tries(st, secure_device, keyslot) == Some(0) {
+            reset_locked_out(st);
+        }
         let password = get_password_of_keyslot(st, config, keyslot, cached)?;
-        let password_or_raw = match keyslot.source {
-            KeyslotSource::Stdin => PasswordOrRaw::Password(&password),
-            KeyslotSource::File(_) => PasswordOrRaw::Raw(&password),
-        };
-        match secure_device.unlock(password_or_raw) {
-            Ok(()) => break,
-            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+        match op(secure_device, password_or_raw(keyslot, &password), opal_authority(keyslot.authority)) {
+            Ok(res) => return Ok(res),
+            Err(opal::Error::Authentication { .. }) => {
+                // don't hand the wrong password to the next drive using this keyslot
+                config.keyslot_buffer.borrow_mut().remove(&keyslot.name);
+                if !matches!(keyslot.source, KeyslotSource::Stdin) {
+                    return Err(Error::new_without_source(format!("keyslot {}: the drive rejected the key", keyslot.name)));
+                }
                 log::error!("Invalid Password, try again!");
             }
             Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::AUTHORITY_LOCKED_OUT }, .. }) => {
-                let mut st = unsafe { st.unsafe_clone() };
-                st.stdout()
-                    .write_str("Too many bad tries, SED locked out, resetting in 10s..")
-                    .unwrap();
-                sleep(Duration::from_secs(10));
-                st.runtime_services()
-                    .reset(ResetType::COLD, Status::WARN_RESET_REQUIRED, None);
+                reset_locked_out(st);
+            }
+            // the password is right, the configuration asks for something its authority may not do
+            Err(e @ opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+                return Err(Error::new(e, format!("{context}: keyslot {} isn't allowed to, check its authority and locking ranges", keyslot.name)));
             }
-            Err(e) => return Err(Error::new(e, "efi error trying to unlock device")),
+            Err(e) => return Err(Error::new(e, context)),
         }
         cached = Cache::Discard;
     }
-    Ok(())
+}
+
+/// the authority takes no more passwords until the drive is power cycled, which a cold reset does
+fn reset_locked_out(st: &SystemTable<Boot>) -> ! {
+    let mut st = unsafe { st.unsafe_clone() };
+    st.stdout()
+        .write_str("Too many bad tries, SED locked out, resetting in 10s..")
+        .unwrap();
+    sleep(Duration::from_secs(10));
+    st.runtime_services()
+        .reset(ResetType::COLD, Status::WARN_RESET_REQUIRED, None)
+}
+
+/// tells the user how many passwords they may still get wrong, if the drive lets us read it;
+/// returns the remaining attempts
+fn show_remaining_tries<P: opal::SecureProtocol>(st: &SystemTable<Boot>, secure_device: &mut opal::OpalDrive<P>, keyslot: &Keyslot) -> Option<u32>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let tries = match secure_device.remaining_tries(opal_authority(keyslot.authority)) {
+        Ok(tries) => tries,
+        Err(e) => {
+            let e: ErrorSource = e.into();
+            log::warn!("{}: can't read the remaining attempts: {e:?}", keyslot.name);
+            return None;
+        }
+    };
+    let mut st = unsafe { st.unsafe_clone() };
+    match tries {
+        Some(0) => st.stdout().write_str(&format!("Keyslot {} is locked out until the drive is power cycled\r\n", keyslot.name)).unwrap(),
+        Some(1) => st.stdout().write_str(&format!(
+            "WARNING: LAST ATTEMPT! Another wrong password locks keyslot {} out of the drive until it's power cycled\r\n",
+            keyslot.name,
+        )).unwrap(),
+        Some(n) => st.stdout().write_str(&format!("{n} attempts left\r\n")).unwrap(),
+        None => (),
+    }
+    tries
+}
+
+/// typed passwords are hashed, keys from files and the DataStore are used as is
+fn password_or_raw<'a>(keyslot: &Keyslot, password: &'a [u8]) -> PasswordOrRaw<'a> {
+    match (&keyslot.source, &keyslot.opal_hash) {
+        (KeyslotSource::Stdin, None) => PasswordOrRaw::Password(password),
+        (KeyslotSource::Stdin, Some(hash)) => PasswordOrRaw::Hashed(password, opal_pin_hash(hash)),
+        (KeyslotSource::File(_) | KeyslotSource::DataStore(_), _) => PasswordOrRaw::Raw(password),
+    }
+}
+
+fn opal_pin_hash(hash: &OpalHash) -> opal::PinHash {
+    let prf = match hash.algorithm {
+        OpalHashAlgorithm::Plain => return opal::PinHash::Plain,
+        OpalHashAlgorithm::Pbkdf2Sha1 => opal::Prf::HmacSha1,
+        OpalHashAlgorithm::Pbkdf2Sha256 => opal::Prf::HmacSha256,
+        OpalHashAlgorithm::Pbkdf2Sha512 => opal::Prf::HmacSha512,
+    };
+    let salt = match &hash.salt {
+        OpalSalt::Serial => opal::Salt::Serial,
+        OpalSalt::None => opal::Salt::None,
+        OpalSalt::Fixed(salt) => opal::Salt::Fixed(salt.as_bytes().to_vec()),
+    };
+    opal::PinHash::Pbkdf2 { prf, iterations: hash.iterations, salt, len: hash.length }
+}
+
+fn opal_authority(authority: OpalAuthority) -> opal::Authority {
+    match authority {
+        OpalAuthority::Sid => opal::Authority::Sid,
+        OpalAuthority::Admin(n) => opal::Authority::Admin(n),
+        OpalAuthority::User(n) => opal::Authority::User(n),
+    }
 }
 
 fn find_read_file(st: &SystemTable<Boot>, config: &Config, mut partitions: &[&Partition], file: &str) -> Result<Vec<u8>> {
@@ -462,39 +962,14 @@ fn find_read_file(st: &SystemTable<Boot>, config: &Config, mut partitions: &[&Pa
         log::debug!("probing blockio #{i} {start_lba:#x} - {end_lba:#x}");
 
         // probe OPAL
-        if let Some(nvme) = try_get_nvme_device(st, blockio_handle)? {
-            let serial = core::str::from_utf8(nvme.serial_num())
-                .context("can't convert nvme serial number to UTF8")?
-                .trim();
-            log::debug!("found nvme with serial: `{}`", serial);
+        if let Some(serial) = opal_drive_serial(st, blockio_handle) {
+            log::debug!("found opal drive with serial: `{}`", serial);
 
             if partitions[0].uuid == serial {
                 // decrypt
-                if partitions[0].keyslot.is_some() {
-                    let keyslot = partitions[0].keyslot.as_deref().unwrap();
-                    let keyslot = &config.keyslots[keyslot];
-                    let secure_device = opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)).unwrap();
-                    unlock_opal(st, secure_device, config, keyslot)?;
-                }
-                partitions = &partitions[1..];
-                if partitions.is_empty() {
-                    return Err(Error::new_without_source("out of partitions"));
-                }
-            }
-        }
-
-        if let Some(mut ata) = try_get_ata_device(st, blockio_handle)? {
-            let serial = core::str::from_utf8(ata.serial())
-                .context("can't convert ATA serial number to UTF8")?
-                .trim();
-            log::debug!("found ATA with serial: `{}`", serial);
-
-            if partitions[0].uuid == serial {
-                // decrypt
-                if partitions[0].keyslot.is_some() {
-                    let keyslot = partitions[0].keyslot.as_deref().unwrap();
-                    let keyslot = &config.keyslots[keyslot];
-                    unlock_opal(st, ata, config, keyslot)?;
+                if !partitions[0].opal_credentials().is_empty() {
+                    with_opal_drive!(st, blockio_handle, |drive| unlock_opal(st, drive, config, partitions[0]))?
+                        .ok_or_else(|| opal_drive_gone(&serial))?;
                 }
                 partitions = &partitions[1..];
                 if partitions.is_empty() {
@@ -691,6 +1166,16 @@ fn get_password_of_keyslot(st: &SystemTable<Boot>, config: &Config, keyslot: &Ke
         KeyslotSource::File(file) => {
             resolve_and_read_file(st, config, file)?
         }
+        KeyslotSource::DataStore(datastore) => {
+            // unlocking the drive reads its DataStore keyslots into the buffer
+            let partition = config.partitions.values().find(|part| part.uuid == datastore.datastore)
+                .ok_or_else(|| Error::new_without_source(format!("keyslot {}: no partition configured for drive `{}`", keyslot.name, datastore.datastore)))?;
+            let blockio_handle = find_opal_drive(st, &datastore.datastore)?;
+            with_opal_drive!(st, blockio_handle, |drive| unlock_opal(st, drive, config, partition))?
+                .ok_or_else(|| opal_drive_gone(&datastore.datastore))?;
+            config.keyslot_buffer.borrow().get(&keyslot.name).cloned()
+                .ok_or_else(|| Error::new_without_source(format!("keyslot {}: drive `{}` has no keyslot to read its DataStore", keyslot.name, datastore.datastore)))?
+        }
     };
     config.keyslot_buffer.borrow_mut().insert(keyslot.name.clone(), password.clone());
     Ok(password)
@@ -0,0 +1,57 @@
+use sha2::{Digest, Sha256};
+
+/// Hash-based random number generator for everything that needs randomness in the greeter,
+/// e.g. PINs, salts and the random seed of the kernel.
+///
+/// Every source of entropy is hashed into the key. Output blocks are `SHA-256(key || counter)`
+/// and the key is replaced after every request, so earlier output can't be recovered from the state.
+#[derive(Default)]
+pub struct Rng {
+    key: [u8; 32],
+    counter: u64,
+    sources: usize,
+}
+
+impl core::fmt::Debug for Rng {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // the key stays out of logs
+        f.debug_struct("Rng").field("sources", &self.sources).finish_non_exhaustive()
+    }
+}
+
+impl Rng {
+    /// Mixes a source of entropy into the key
+    pub fn mix(&mut self, entropy: &[u8]) {
+        self.key = Sha256::new()
+            .chain_update(b"mix")
+            .chain_update(self.key)
+            .chain_update(entropy)
+            .finalize()
+            .into();
+        self.sources += 1;
+    }
+
+    /// Whether any entropy has been mixed in, the output is predictable otherwise
+    pub fn is_seeded(&self) -> bool {
+        self.sources > 0
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(32) {
+            let block = self.block(b"out");
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.key = self.block(b"rekey");
+    }
+
+    fn block(&mut self, label: &[u8]) -> [u8; 32] {
+        let block = Sha256::new()
+            .chain_update(label)
+            .chain_update(self.key)
+            .chain_update(self.counter.to_le_bytes())
+            .finalize()
+            .into();
+        self.counter += 1;
+        block
+    }
+}